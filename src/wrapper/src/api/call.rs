@@ -1,90 +1,945 @@
-use candid::Principal;
-use ic_papi_api::PaymentType;
+use candid::{decode_one, encode_one, CandidType, Encode, Principal};
+use futures::future::join_all;
+use ic_management_canister_types::{
+    EcdsaPublicKeyArgs, SchnorrPublicKeyArgs, SignWithEcdsaArgs, SignWithSchnorrArgs,
+};
+use ic_papi_api::cycles::cycles_ledger_canister_id;
+use ic_papi_api::{PaymentError, PaymentType};
+use serde::de::DeserializeOwned;
+use sha2::{Digest, Sha256};
 
-use crate::domain::errors::BridgeError;
-use crate::domain::types::{BridgeCallArgs, MethodKey};
-use crate::payments::guard_config::PAYMENT_GUARD;
-use crate::state;
+use crate::api::validate::{check_method_target, validate_config};
+use crate::domain::errors::{BridgeError, RejectCode, TargetReject};
+use crate::domain::types::{
+    BridgeCallArgs, BridgeReply, CacheKey, CallBatchArgs, CallerEnvelope, Charge, CostEstimate,
+    FeeDenom, FeeSpec, MethodConfig, MethodKey, PaymentReceipt, RequestKey, TokenCyclesRate,
+};
+use crate::payments::guard::PaymentGuard;
+use crate::payments::refund::refund;
+use crate::store::ConfigStore;
 use crate::util::cycles::forward_raw;
+use crate::util::management::canister_info;
 
 /// The IC management canister principal (`aaaaa-aa`).
 ///
 /// Forwarding to it would let any caller make inter-canister calls that are
 /// authorized as *this* canister, e.g. lifecycle operations against any
-/// canister the bridge controls.
-///
-/// Note that not every management-canister method is controller-gated: some
-/// (e.g. `raw_rand`, threshold `sign_with_ecdsa`/`sign_with_schnorr`, the
-/// Bitcoin API) could be legitimate paid targets. Even so, the bridge is
-/// currently a target *denylist* (it forbids only itself and `aaaaa-aa`), so
-/// exposing individual safe methods must not be done by unblocking the whole
-/// principal — the growing management API makes that too easy to get wrong.
-/// Instead, opt specific methods in via the `MethodConfig`/`MethodKey`
-/// allowlist in `domain::types`. Until that allowlist is wired up, block
-/// `aaaaa-aa` outright.
+/// canister the bridge controls. Only the methods in
+/// [`PRICEABLE_MANAGEMENT_METHODS`] may be reached, and only once an operator
+/// has priced them; everything else on `aaaaa-aa` is blocked.
 const MANAGEMENT_CANISTER_ID: Principal = Principal::management_canister();
 
-fn map_guard_err<E: core::fmt::Debug>(e: E) -> String {
-    BridgeError::GuardError(format!("{e:?}")).to_string()
+/// The most calls a single `call_batch` may make.
+pub const MAX_BATCH_CALLS: usize = 10;
+
+/// Management-canister methods that an operator may price and resell.
+///
+/// None of these can act on another canister's lifecycle or settings: they
+/// return randomness, make HTTPS outcalls, or use the wrapper's threshold keys.
+/// Keys are derived with the caller's principal in front of the
+/// `derivation_path` it chooses (see [`scope_keys_to_caller`]), so that no
+/// caller can use another's keys. HTTPS outcalls cannot use a `transform`,
+/// since the transform function must live on the wrapper.
+///
+/// Lifecycle and settings methods (`create_canister`, `install_code`,
+/// `update_settings`, `stop_canister`, `delete_canister`, ...) are permanently
+/// blocked, as is any method added to the management canister later. So are
+/// the deprecated `bitcoin_*` methods; the Bitcoin canister can be priced
+/// directly instead.
+pub const PRICEABLE_MANAGEMENT_METHODS: &[&str] = &[
+    "raw_rand",
+    "http_request",
+    "ecdsa_public_key",
+    "sign_with_ecdsa",
+    "schnorr_public_key",
+    "sign_with_schnorr",
+];
+
+/// Whether `(target, method)` may be reached through the bridge at all,
+/// regardless of pricing.
+///
+/// # Errors
+/// - [`BridgeError::SelfCall`] if the target is the wrapper itself.
+/// - [`BridgeError::ForbiddenTarget`] if the target is the management canister
+///   and the method is not in [`PRICEABLE_MANAGEMENT_METHODS`].
+pub fn check_target(target: Principal, method: &str) -> Result<(), BridgeError> {
+    if target == ic_cdk::api::canister_self() {
+        return Err(BridgeError::SelfCall);
+    }
+    if target == MANAGEMENT_CANISTER_ID && !PRICEABLE_MANAGEMENT_METHODS.contains(&method) {
+        return Err(BridgeError::ForbiddenTarget(format!(
+            "the management canister method `{method}` may not be reached through the bridge."
+        )));
+    }
+    Ok(())
 }
 
-/// Internal helper to unify the bridge logic: look up price -> charge fee -> forward call.
+/// Gives each caller its own threshold keys, by putting its principal in front
+/// of the `derivation_path` of calls to the management canister's key methods.
+///
+/// Without this, the wrapper would sign for any caller with any key derived
+/// from its own, including keys that other callers use.
 ///
-/// The fee and the number of cycles to forward are taken from the
-/// operator-configured [`crate::domain::types::MethodConfig`] for the
-/// `(target, method)` pair — never from the caller. This prevents a caller from
-/// naming a trivial fee while forwarding a large amount, which would drain the
-/// wrapper's own cycle balance.
-// TODO: The caller may have to provide more type information than they are used to. Normally dfx will use the target canister's candid file to convert to the correct types; without that information it will guess more simply and won't always get this conversion right.
-pub async fn bridge_call(args: BridgeCallArgs) -> Result<Vec<u8>, String> {
-    if args.target == ic_cdk::api::canister_self() {
-        return Err("Self-calls are not allowed through the bridge.".to_string());
-    }
-
-    // The bridge must never be usable as a proxy to the management canister:
-    // such calls would execute with the bridge's own principal as the caller.
-    if args.target == MANAGEMENT_CANISTER_ID {
-        return Err(BridgeError::ForbiddenTarget(
-            "the management canister may not be reached through the bridge.".to_string(),
-        )
-        .to_string());
-    }
-
-    // Look up the operator-configured price for this `(target, method)`.
-    let key = MethodKey {
-        target: args.target,
-        method: args.method.clone(),
+/// # Errors
+/// [`BridgeError::Candid`] if the arguments of a key method do not decode.
+pub fn scope_keys_to_caller(
+    caller: Principal,
+    args: &mut BridgeCallArgs,
+) -> Result<(), BridgeError> {
+    if args.target != MANAGEMENT_CANISTER_ID {
+        return Ok(());
+    }
+    args.args = match args.method.as_str() {
+        "ecdsa_public_key" => {
+            prefix_derivation_path(&args.args, caller, |a: &mut EcdsaPublicKeyArgs| {
+                &mut a.derivation_path
+            })?
+        }
+        "sign_with_ecdsa" => {
+            prefix_derivation_path(&args.args, caller, |a: &mut SignWithEcdsaArgs| {
+                &mut a.derivation_path
+            })?
+        }
+        "schnorr_public_key" => {
+            prefix_derivation_path(&args.args, caller, |a: &mut SchnorrPublicKeyArgs| {
+                &mut a.derivation_path
+            })?
+        }
+        "sign_with_schnorr" => {
+            prefix_derivation_path(&args.args, caller, |a: &mut SignWithSchnorrArgs| {
+                &mut a.derivation_path
+            })?
+        }
+        _ => return Ok(()),
     };
-    let config = state::get_config(&key).ok_or_else(|| {
-        BridgeError::MethodNotConfigured {
-            target: args.target.to_string(),
+    Ok(())
+}
+
+/// Re-encodes the Candid `args` of a key method with `caller` in front of
+/// their derivation path.
+fn prefix_derivation_path<T: CandidType + DeserializeOwned>(
+    args: &[u8],
+    caller: Principal,
+    path: impl FnOnce(&mut T) -> &mut Vec<Vec<u8>>,
+) -> Result<Vec<u8>, BridgeError> {
+    let mut decoded: T = decode_one(args).map_err(|e| BridgeError::Candid(e.to_string()))?;
+    path(&mut decoded).insert(0, caller.as_slice().to_vec());
+    encode_one(decoded).map_err(|e| BridgeError::Candid(e.to_string()))
+}
+
+/// The bridge: prices, charges for and forwards calls.
+///
+/// Prices and bookkeeping come from the `store`, and fees are taken through
+/// the `guard`. The standalone wrapper canister uses its own stable state and
+/// guard; another canister can embed the bridge with its own.
+pub struct Bridge<'a, S, G> {
+    store: &'a S,
+    guard: &'a G,
+}
+
+impl<'a, S: ConfigStore, G: PaymentGuard> Bridge<'a, S, G> {
+    #[must_use]
+    pub fn new(store: &'a S, guard: &'a G) -> Self {
+        Self { store, guard }
+    }
+
+    /// The payment type the caller named, or else its payment preference.
+    fn payment_or_preference(
+        &self,
+        payment: Option<PaymentType>,
+        caller: &Principal,
+    ) -> Option<PaymentType> {
+        payment.or_else(|| {
+            self.store
+                .use_payment_preference(caller, ic_cdk::api::time())
+        })
+    }
+
+    /// Proxies a call for `caller`: look up price -> charge fee -> forward call.
+    ///
+    /// The fee and the number of cycles to forward are taken from the
+    /// operator-configured [`crate::domain::types::MethodConfig`] for the
+    /// `(target, method)` pair — never from the caller. This prevents a caller from
+    /// naming a trivial fee while forwarding a large amount, which would drain the
+    /// wrapper's own cycle balance.
+    ///
+    /// If the caller supplies a `request_id`, the outcome of a charged call is
+    /// remembered for as long as the store keeps it, and a retry with the same
+    /// id and the same arguments returns it without charging or calling the
+    /// target again.
+    ///
+    /// A caller that names no payment type pays with its payment preference, if
+    /// it set one, and otherwise with attached cycles.
+    // TODO: The caller may have to provide more type information than they are used to. Normally dfx will use the target canister's candid file to convert to the correct types; without that information it will guess more simply and won't always get this conversion right.
+    pub async fn call(
+        &self,
+        caller: Principal,
+        mut args: BridgeCallArgs,
+    ) -> Result<BridgeReply, BridgeError> {
+        let Some(request_id) = args.request_id.clone() else {
+            args.payment = self.payment_or_preference(args.payment, &caller);
+            return self.proxy(caller, args).await.into_result();
+        };
+        // A retry is compared with the call as the caller made it, before its
+        // payment preference fills in the payment type.
+        let fingerprint = args.fingerprint();
+        args.payment = self.payment_or_preference(args.payment, &caller);
+        let key = (caller, request_id);
+        let now = ic_cdk::api::time();
+        if let Some(outcome) =
+            self.store
+                .begin_request(key.clone(), args.target, &args.method, fingerprint, now)?
+        {
+            return outcome;
+        }
+        let outcome = self.proxy(caller, args).await;
+        self.settle_request(&key, &outcome);
+        outcome.into_result()
+    }
+
+    /// Records the outcome of a call made with a request id: a charged call is
+    /// remembered, so that a retry replays it, and one that charged nothing is
+    /// forgotten, so that a retry is free to try again.
+    fn settle_request(&self, key: &RequestKey, outcome: &ProxyOutcome) {
+        match outcome {
+            ProxyOutcome::NotCharged(_) => self.store.abandon_request(key),
+            ProxyOutcome::Charged(result) => self.store.complete_request(key, result.clone()),
+        }
+    }
+
+    /// Checks that the call may be made and prices it, without side effects.
+    ///
+    /// The call is priced as if its arguments were `request_bytes` long. With
+    /// `lookup_cache`, a call whose reply is cached is priced at the cache-hit fee.
+    fn price(
+        &self,
+        args: &BridgeCallArgs,
+        request_bytes: u64,
+        lookup_cache: bool,
+    ) -> Result<Price, BridgeError> {
+        // The bridge must never be usable as a proxy for lifecycle operations:
+        // management calls execute with the bridge's own principal as the caller.
+        check_target(args.target, &args.method)?;
+
+        // Look up the operator-configured price for this `(target, method)`, or
+        // the pricing rule that covers it.
+        let key = MethodKey {
+            target: args.target,
             method: args.method.clone(),
+        };
+        self.store.apply_due_configs(ic_cdk::api::time());
+        let config =
+            self.store
+                .resolve_config(&key)
+                .ok_or_else(|| BridgeError::MethodNotConfigured {
+                    target: args.target,
+                    method: args.method.clone(),
+                })?;
+        // The store may be filled by an embedder rather than through the
+        // wrapper's validated endpoints, so every resolved price is checked.
+        check_method_target(&key, &config)
+            .and_then(|()| validate_config(&config, |ledger| self.store.get_rate(ledger)))
+            .map_err(|reason| BridgeError::MethodMisconfigured {
+                target: args.target,
+                method: args.method.clone(),
+                reason,
+            })?;
+
+        // Refuse calls that the operator or the circuit breaker has stopped,
+        // before the caller is charged.
+        self.store.check_not_paused(&key)?;
+        self.store.check_circuit(args.target, ic_cdk::api::time())?;
+        self.store.check_module_pin(args.target)?;
+
+        let p = args.payment.clone().unwrap_or(PaymentType::AttachedCycles);
+
+        // Serve repeated identical calls to a cacheable method from the cache, at
+        // the cache-hit price and without forwarding any cycles.
+        let cache_key = config
+            .cache
+            .as_ref()
+            .map(|_| (key, Sha256::digest(&args.args).into()));
+        let cached = cache_key
+            .as_ref()
+            .filter(|_| lookup_cache)
+            .and_then(|key| self.store.get_cached_reply(key, ic_cdk::api::time()));
+
+        // The caller may only choose the forwarded amount within the operator's
+        // range, and the fee grows with it, and with the size of the call.
+        let (charge, reply_reserve) = match (&cached, &config.cache) {
+            (Some(_), Some(cache)) => (
+                Charge {
+                    fee: cache.hit_fee,
+                    forward_cycles: 0,
+                },
+                0,
+            ),
+            _ => {
+                let mut charge = config.charge(args.forward_cycles)?;
+                let (request, reply_reserve) = match &config.size_pricing {
+                    Some(size) => size.charge(request_bytes)?,
+                    None => (0, 0),
+                };
+                charge.fee = charge
+                    .fee
+                    .checked_add(request)
+                    .and_then(|fee| fee.checked_add(reply_reserve))
+                    .ok_or(BridgeError::PriceOverflow)?;
+                (charge, reply_reserve)
+            }
+        };
+
+        // The price may have changed since the caller looked it up.
+        if let Some(max_fee) = &args.max_fee {
+            let fee = FeeSpec {
+                amount: charge.fee,
+                denom: config.fee.denom.clone(),
+            };
+            if !fee.fits_within(max_fee) {
+                return Err(BridgeError::FeeExceedsMax {
+                    fee,
+                    max_fee: max_fee.clone(),
+                });
+            }
+        }
+
+        Ok(Price {
+            config,
+            payment: p,
+            charge,
+            reply_reserve,
+            cache_key,
+            cached,
+        })
+    }
+
+    /// How the cycles forwarded for a priced call are paid for.
+    ///
+    /// If this method forwards cycles, the payment must fund them. Cycle payments
+    /// credit the wrapper's cycle balance directly: `set_method_config` already
+    /// guarantees `fee.denom == Cycles` and that the fee covers the forwarded
+    /// cycles for such methods. Token payments credit a token account instead, so
+    /// the cycles come out of the operator-funded reserve, provided the fee is
+    /// worth at least the forwarded cycles at the operator's rate.
+    fn funding(&self, price: &Price) -> Result<CycleFunding, BridgeError> {
+        let cycles = price.charge.forward_cycles;
+        if cycles == 0 || is_cycle_payment(&price.payment) {
+            return Ok(CycleFunding::Payment);
+        }
+        let rate = token_ledger(&price.payment)
+            .and_then(|ledger| self.store.get_rate(&ledger))
+            .ok_or(BridgeError::ForwardRequiresCyclePayment)?;
+        // The reply reservation may be returned, so it cannot fund the cycles.
+        let fee_in_cycles = rate.to_cycles(price.charge.fee.saturating_sub(price.reply_reserve));
+        if fee_in_cycles < cycles {
+            return Err(BridgeError::FeeDoesNotCoverForwardedCycles {
+                fee_in_cycles,
+                forward_cycles: cycles,
+            });
+        }
+        Ok(CycleFunding::Reserve(rate))
+    }
+
+    /// Checks that the call may be made and prices it, without charging.
+    ///
+    /// If the forwarded cycles come from the reserve, they are set aside here and
+    /// must be returned if the call does not go ahead.
+    fn quote(&self, args: &BridgeCallArgs) -> Result<Quote, BridgeError> {
+        let price = self.price(args, args.args.len() as u64, true)?;
+        let funding = self.funding(&price)?;
+        self.set_aside(&funding, price.charge.forward_cycles)?;
+        let Price {
+            config,
+            payment,
+            charge,
+            reply_reserve,
+            cache_key,
+            cached,
+        } = price;
+        Ok(Quote {
+            config,
+            payment,
+            charge,
+            reply_reserve,
+            funding,
+            cache_key,
+            cached,
+        })
+    }
+
+    /// Takes the `cycles` a call forwards out of the reserve, if the reserve
+    /// funds them. Callers must do so before their first `await`, so that
+    /// concurrent calls cannot overdraw the reserve.
+    fn set_aside(&self, funding: &CycleFunding, cycles: u128) -> Result<(), BridgeError> {
+        if let CycleFunding::Reserve(_) = funding {
+            self.store.take_from_reserve(cycles).map_err(|available| {
+                BridgeError::InsufficientCyclesReserve {
+                    needed: cycles,
+                    available,
+                }
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Returns the cycles set aside for a call that will not go ahead.
+    fn release(&self, quote: &Quote) {
+        if let CycleFunding::Reserve(_) = quote.funding {
+            self.store.return_to_reserve(quote.charge.forward_cycles);
+        }
+    }
+
+    /// What a call by `caller` would cost with the given payment, or its
+    /// payment preference, priced exactly as
+    /// [`Bridge::call`] would charge it, but without charging or setting aside
+    /// any cycles.
+    ///
+    /// The estimate is for a call whose reply is not cached.
+    ///
+    /// # Errors
+    /// If the call would be refused whatever the payment, e.g. because the method
+    /// is not configured or is paused. Reasons specific to the payment type are
+    /// reported in [`CostEstimate::rejection`] instead.
+    pub fn estimate_cost(
+        &self,
+        caller: Principal,
+        mut args: BridgeCallArgs,
+        request_bytes: u64,
+    ) -> Result<CostEstimate, BridgeError> {
+        args.payment = self.payment_or_preference(args.payment, &caller);
+        let price = self.price(&args, request_bytes, false)?;
+        let fee = price.charge.fee;
+        let cycles = price.charge.forward_cycles;
+        let rejection = if !self.guard.accepts(&price.payment) {
+            Some(BridgeError::from(PaymentError::UnsupportedPaymentType))
+        } else {
+            match self.funding(&price) {
+                Ok(CycleFunding::Reserve(_)) => {
+                    let available = self.store.reserve_balance();
+                    (available < cycles).then_some(BridgeError::InsufficientCyclesReserve {
+                        needed: cycles,
+                        available,
+                    })
+                }
+                Ok(CycleFunding::Payment) => None,
+                Err(e) => Some(e),
+            }
+        };
+        let ledger_fee =
+            payment_ledger(&price.payment).map(|ledger| self.store.get_ledger_fee(&ledger));
+        let required_allowance = match ledger_fee {
+            Some(Some(ledger_fee)) => Some(fee.saturating_add(ledger_fee)),
+            _ => None,
+        };
+        Ok(CostEstimate {
+            fee: FeeSpec {
+                amount: fee,
+                denom: price.config.fee.denom,
+            },
+            forward_cycles: cycles,
+            reply_reserve: price.reply_reserve,
+            ledger_fee: ledger_fee.flatten(),
+            required_allowance,
+            payment_accepted: rejection.is_none(),
+            rejection,
+        })
+    }
+
+    /// Checks that a target whose module hash is pinned still runs that module.
+    ///
+    /// A hash found to match is trusted for a short while without being looked
+    /// up again. The target could still be upgraded between this check and the
+    /// call, but a change is caught once the trusted hash expires.
+    async fn verify_module(&self, target: Principal) -> Result<(), BridgeError> {
+        if !self.store.is_module_pinned(&target) {
+            return Ok(());
+        }
+        self.store.check_module_pin(target)?;
+        if self
+            .store
+            .module_recently_verified(&target, ic_cdk::api::time())
+        {
+            return Ok(());
+        }
+        let info = canister_info(target)
+            .await
+            .map_err(|reason| BridgeError::ModuleHashUnavailable { target, reason })?;
+        self.store
+            .record_module_hash(target, info.module_hash, ic_cdk::api::time())
+    }
+
+    /// Charges for and forwards a single call.
+    async fn proxy(&self, caller: Principal, mut args: BridgeCallArgs) -> ProxyOutcome {
+        if let Err(e) = scope_keys_to_caller(caller, &mut args) {
+            return ProxyOutcome::NotCharged(e);
+        }
+        // Price the call before looking up the target's module, so that calls
+        // that would be refused anyway cost the wrapper no inter-canister call.
+        let quote = match self.quote(&args) {
+            Ok(quote) => quote,
+            Err(e) => return ProxyOutcome::NotCharged(e),
+        };
+        if let Err(e) = self.verify_module(args.target).await {
+            self.release(&quote);
+            return ProxyOutcome::NotCharged(e);
+        }
+        // 1) Charge the operator-set fee.
+        if let Err(e) = self
+            .charge(
+                &quote.payment,
+                quote.charge.fee,
+                std::slice::from_ref(&quote),
+            )
+            .await
+        {
+            return ProxyOutcome::NotCharged(e);
+        }
+        ProxyOutcome::Charged(self.execute(caller, args, quote).await)
+    }
+
+    /// Charges once for a batch of calls, then forwards each of them.
+    ///
+    /// Every call is checked and priced before anything is charged, so a batch
+    /// that names an unpriced or forbidden method costs nothing. Once the sum of
+    /// the prices has been charged, each call succeeds or fails on its own, exactly
+    /// as if it had been made through `call_blob` and paid for separately.
+    ///
+    /// # Errors
+    /// If the batch is empty or too large, if any call fails its checks or is
+    /// priced in another denomination than the payment, or if the payment
+    /// fails. Failures of individual calls are reported per call.
+    pub async fn call_batch(
+        &self,
+        caller: Principal,
+        mut args: CallBatchArgs,
+    ) -> Result<Vec<Result<BridgeReply, BridgeError>>, BridgeError> {
+        let parallel = args.parallel.unwrap_or(false);
+        args.payment = self.payment_or_preference(args.payment, &caller);
+        let payment = args.payment.clone().unwrap_or(PaymentType::AttachedCycles);
+        let mut calls = args.into_bridge_calls();
+        if calls.is_empty() {
+            return Err(BridgeError::EmptyBatch);
+        }
+        if calls.len() > MAX_BATCH_CALLS {
+            return Err(BridgeError::BatchTooLarge {
+                max_calls: MAX_BATCH_CALLS as u64,
+            });
+        }
+
+        for call in &mut calls {
+            scope_keys_to_caller(caller, call)?;
+        }
+
+        let mut quotes = Vec::with_capacity(calls.len());
+        for call in &calls {
+            match self.quote(call) {
+                Ok(quote) => quotes.push(quote),
+                Err(e) => {
+                    quotes.iter().for_each(|quote| self.release(quote));
+                    return Err(e);
+                }
+            }
+        }
+        if let Err(e) = check_batch_denom(&payment, quotes.iter().map(|q| &q.config.fee.denom)) {
+            quotes.iter().for_each(|quote| self.release(quote));
+            return Err(e);
+        }
+        let Some(total) = quotes
+            .iter()
+            .try_fold(0u128, |total, quote| total.checked_add(quote.charge.fee))
+        else {
+            quotes.iter().for_each(|quote| self.release(quote));
+            return Err(BridgeError::PriceOverflow);
+        };
+
+        let mut targets: Vec<_> = calls.iter().map(|call| call.target).collect();
+        targets.sort();
+        targets.dedup();
+        for target in targets {
+            if let Err(e) = self.verify_module(target).await {
+                quotes.iter().for_each(|quote| self.release(quote));
+                return Err(e);
+            }
+        }
+        self.charge(&payment, total, &quotes).await?;
+
+        let executions = calls
+            .into_iter()
+            .zip(quotes)
+            .map(|(call, quote)| self.execute(caller, call, quote));
+        if parallel {
+            Ok(join_all(executions).await)
+        } else {
+            let mut results = Vec::with_capacity(executions.len());
+            for execution in executions {
+                results.push(execution.await);
+            }
+            Ok(results)
+        }
+    }
+
+    /// Deducts `fee` for the `quotes`, releasing the cycles they set aside if the
+    /// payment fails.
+    async fn charge(
+        &self,
+        payment: &PaymentType,
+        fee: u128,
+        quotes: &[Quote],
+    ) -> Result<(), BridgeError> {
+        self.guard.deduct(payment.clone(), fee).await.map_err(|e| {
+            quotes.iter().for_each(|quote| self.release(quote));
+            BridgeError::from(e)
+        })
+    }
+
+    /// Forwards a call that has been paid for.
+    async fn execute(
+        &self,
+        caller: Principal,
+        args: BridgeCallArgs,
+        quote: Quote,
+    ) -> Result<BridgeReply, BridgeError> {
+        let Quote {
+            config,
+            payment: p,
+            charge,
+            reply_reserve,
+            funding,
+            cache_key,
+            cached,
+        } = quote;
+        let cycles = charge.forward_cycles;
+
+        if let Some(reply) = cached {
+            self.credit_owner(args.target, &config, charge.fee);
+            return Ok(BridgeReply {
+                reply: reply.into(),
+                cycles_forwarded: 0,
+                cycles_unused: 0,
+                cycles_unused_error: None,
+                reply_reserve_returned: 0,
+                reply_reserve_error: None,
+                from_cache: true,
+            });
+        }
+
+        // 2) Forward the call with the charged-for cycles, wrapped in an envelope
+        //    that vouches for the caller if the method asks for one.
+        let wait = config.wait.unwrap_or_default();
+        let payload = if config.forwards_caller() {
+            let envelope = CallerEnvelope {
+                caller,
+                target: args.target,
+                method: args.method.clone(),
+                receipt: PaymentReceipt {
+                    payment: p.clone(),
+                    fee: FeeSpec {
+                        amount: charge.fee,
+                        denom: config.fee.denom.clone(),
+                    },
+                    forward_cycles: cycles,
+                    request_id: args.request_id.clone(),
+                    forwarded_at: ic_cdk::api::time(),
+                },
+                args: args.args.into(),
+            };
+            Encode!(&envelope).expect("a caller envelope is always encodable")
+        } else {
+            args.args
+        };
+        let (result, cycles_unused) =
+            forward_raw(args.target, &args.method, payload, cycles, wait).await;
+        match &result {
+            Ok(_) => self.store.record_success(&args.target),
+            Err(reason) if is_target_failure(reason) => {
+                self.store.record_failure(args.target, ic_cdk::api::time());
+            }
+            Err(_) => {}
+        }
+        let kept_in_fee_units = funding.cost_of(cycles.saturating_sub(cycles_unused));
+        // Cycles funded by the reserve go back to it whatever the outcome.
+        if let CycleFunding::Reserve(_) = funding {
+            self.store.return_to_reserve(cycles_unused);
+        }
+        match result {
+            // 3) Pass any forwarded cycles the target did not accept back to whoever funded them.
+            Ok(reply) => {
+                if let (Some(key), Some(cache)) = (cache_key, &config.cache) {
+                    let now = ic_cdk::api::time();
+                    let expires_at =
+                        now.saturating_add(cache.ttl_seconds.saturating_mul(1_000_000_000));
+                    self.store
+                        .put_cached_reply(key, reply.clone(), now, expires_at);
+                }
+                // 4) Settle the reservation for the reply's size, and return what is
+                //    left of it together with any unused cycles paid for with cycles,
+                //    in a single refund.
+                let reply_reserve_unused = config.size_pricing.as_ref().map_or(0, |size| {
+                    reply_reserve.saturating_sub(size.reply_cost(reply.len() as u64))
+                });
+                let cycles_to_return = funding.payers_share(cycles_unused);
+                let unused = cycles_to_return.saturating_add(reply_reserve_unused);
+                // Unused cycles and reservations belong to the payer, even if they
+                // could not be returned, unless they are worth less than the refund
+                // would cost.
+                let (returned, refund_error) = if unused < self.min_refund(&p) {
+                    (0, None)
+                } else {
+                    (unused, refund(&p, caller, unused).await.err())
+                };
+                let reply_reserve_returned = if returned > 0 {
+                    reply_reserve_unused
+                } else {
+                    0
+                };
+                let cycles_unused_error = refund_error.clone().filter(|_| cycles_to_return > 0);
+                let reply_reserve_error = refund_error.filter(|_| reply_reserve_returned > 0);
+                self.credit_owner(
+                    args.target,
+                    &config,
+                    charge
+                        .fee
+                        .saturating_sub(kept_in_fee_units)
+                        .saturating_sub(returned),
+                );
+                Ok(BridgeReply {
+                    reply: reply.into(),
+                    cycles_forwarded: cycles,
+                    cycles_unused,
+                    cycles_unused_error,
+                    reply_reserve_returned,
+                    reply_reserve_error,
+                    from_cache: false,
+                })
+            }
+            // 3) The target may have done the work: keep the fee, but say so.
+            Err(reason) if reason.reject_code == Some(RejectCode::SysUnknown) => {
+                self.credit_owner(
+                    args.target,
+                    &config,
+                    charge.fee.saturating_sub(kept_in_fee_units),
+                );
+                Err(BridgeError::OutcomeUnknown(reason))
+            }
+            // 3) The caller has paid for nothing: apply the method's refund policy.
+            Err(reason) => {
+                let amount =
+                    rejected_call_refund(&config, charge.fee, reply_reserve, kept_in_fee_units);
+                // A refund worth less than it would cost is kept.
+                let amount = if amount < self.min_refund(&p) {
+                    0
+                } else {
+                    amount
+                };
+                let error = refund_rejected_call(&p, caller, amount, reason).await;
+                let refunded = match &error {
+                    BridgeError::TargetRejectedRefunded { refunded, .. } => *refunded,
+                    BridgeError::RefundFailed { refund, .. } => *refund,
+                    _ => 0,
+                };
+                self.credit_owner(
+                    args.target,
+                    &config,
+                    charge
+                        .fee
+                        .saturating_sub(kept_in_fee_units)
+                        .saturating_sub(refunded),
+                );
+                Err(error)
+            }
+        }
+    }
+
+    /// The smallest amount worth returning to the payer of `payment`: the fee of
+    /// the ledger a refund goes through, if it is known.
+    fn min_refund(&self, payment: &PaymentType) -> u128 {
+        let ledger = if is_cycle_payment(payment) {
+            Some(cycles_ledger_canister_id())
+        } else {
+            token_ledger(payment)
+        };
+        ledger
+            .and_then(|ledger| self.store.get_ledger_fee(&ledger))
+            .unwrap_or(0)
+    }
+
+    /// Credits the owner of `target`, if it is registered, with the `revenue` of a
+    /// call: the fee, less what was refunded and what the forwarded cycles cost,
+    /// in the fee's denomination.
+    fn credit_owner(&self, target: Principal, config: &MethodConfig, revenue: u128) {
+        self.store
+            .credit_owner(&target, config.fee.denom.clone(), revenue);
+    }
+}
+
+/// The result of a proxied call, split by whether the caller was charged.
+enum ProxyOutcome {
+    /// The call failed before any fee was deducted.
+    NotCharged(BridgeError),
+    /// The fee was deducted; this is the (possibly failed) result of the call.
+    Charged(Result<BridgeReply, BridgeError>),
+}
+
+impl ProxyOutcome {
+    fn into_result(self) -> Result<BridgeReply, BridgeError> {
+        match self {
+            ProxyOutcome::NotCharged(e) => Err(e),
+            ProxyOutcome::Charged(result) => result,
+        }
+    }
+}
+
+/// A call that has been priced and may now be charged.
+struct Quote {
+    config: MethodConfig,
+    payment: PaymentType,
+    charge: Charge,
+    /// The part of the fee reserved for the size of the reply.
+    reply_reserve: u128,
+    funding: CycleFunding,
+    /// Where to cache the reply, if the method is cacheable.
+    cache_key: Option<CacheKey>,
+    /// A reply to serve from the cache instead of calling the target.
+    cached: Option<Vec<u8>>,
+}
+
+/// A call that has passed its checks, and what it costs.
+struct Price {
+    config: MethodConfig,
+    payment: PaymentType,
+    charge: Charge,
+    /// The part of the fee reserved for the size of the reply.
+    reply_reserve: u128,
+    /// Where to cache the reply, if the method is cacheable.
+    cache_key: Option<CacheKey>,
+    /// A reply to serve from the cache instead of calling the target.
+    cached: Option<Vec<u8>>,
+}
+
+/// Where the cycles attached to a forwarded call come from.
+enum CycleFunding {
+    /// The fee was paid in cycles, which fund the forwarded cycles directly.
+    Payment,
+    /// The fee was paid in tokens; the forwarded cycles were taken from the
+    /// reserve, and are worth this many tokens.
+    Reserve(TokenCyclesRate),
+}
+
+impl CycleFunding {
+    /// What the `kept` forwarded cycles that the target accepted cost the
+    /// payer, in the fee's units.
+    fn cost_of(&self, kept: u128) -> u128 {
+        match self {
+            CycleFunding::Payment => kept,
+            CycleFunding::Reserve(rate) => rate.to_tokens(kept),
+        }
+    }
+
+    /// The part of the `unused` forwarded cycles that goes back to the payer,
+    /// rather than to the reserve that funded them.
+    fn payers_share(&self, unused: u128) -> u128 {
+        match self {
+            CycleFunding::Payment => unused,
+            CycleFunding::Reserve(_) => 0,
         }
-        .to_string()
-    })?;
+    }
+}
+
+/// The refundable part of the `fee` charged for a call the target rejected.
+///
+/// The `reply_reserve` set aside for the size of the reply is returned in
+/// full, and the method's refund policy applies to the rest of the fee. The
+/// value of forwarded cycles that the target kept, `kept_by_target` in the
+/// fee's units, is not refunded: those cycles were spent on the caller's behalf
+/// and are no longer in the wrapper's balance.
+fn rejected_call_refund(
+    config: &MethodConfig,
+    fee: u128,
+    reply_reserve: u128,
+    kept_by_target: u128,
+) -> u128 {
+    let policy = config.refund.clone().unwrap_or_default();
+    let fee = fee.saturating_sub(reply_reserve);
+    policy
+        .refund_amount(fee)
+        .min(fee.saturating_sub(kept_by_target))
+        .saturating_add(reply_reserve)
+}
+
+/// Returns `amount` of the fee charged for a call the target rejected.
+async fn refund_rejected_call(
+    payment: &PaymentType,
+    caller: Principal,
+    amount: u128,
+    reason: TargetReject,
+) -> BridgeError {
+    if amount == 0 {
+        return BridgeError::TargetRejected(reason);
+    }
+    match refund(payment, caller, amount).await {
+        Ok(()) => BridgeError::TargetRejectedRefunded {
+            reason,
+            refunded: amount,
+        },
+        Err(error) => BridgeError::RefundFailed {
+            reason,
+            refund: amount,
+            error,
+        },
+    }
+}
 
-    let p = args.payment.unwrap_or(PaymentType::AttachedCycles);
-    let cycles = config.forward_cycles.unwrap_or(0);
+/// Parts of the reject messages with which canisters refuse arguments that do
+/// not decode, in Rust (Candid) and Motoko.
+const ARGUMENT_ERRORS: &[&str] = &["decode", "idl error", "magic number", "parse header"];
 
-    // If this method forwards cycles, the caller must pay in cycles so that the
-    // fee actually credits the wrapper's cycle balance. `set_method_config`
-    // already guarantees `fee.denom == Cycles` and `fee.amount >= forward_cycles`
-    // for such methods; here we additionally ensure the *caller's chosen* payment
-    // type is cycle-denominated (token payments credit a token account, not cycles).
-    if cycles > 0 && !is_cycle_payment(&p) {
-        return Err(BridgeError::ForwardRequiresCyclePayment.to_string());
+/// Whether a failed call counts against the target's circuit breaker.
+///
+/// Only failures of the target itself count: it trapped (other than on
+/// arguments it could not decode), or the system rejected the call on its
+/// behalf, e.g. because it is stopped or out of cycles. Explicit rejects and
+/// undecodable arguments are usually the caller's doing, and a timeout may be
+/// caused by the call itself, so none of these count: any caller could
+/// otherwise trip the breaker for everyone.
+fn is_target_failure(reason: &TargetReject) -> bool {
+    match reason.reject_code {
+        Some(RejectCode::CanisterError) => {
+            let message = reason.message.to_lowercase();
+            !ARGUMENT_ERRORS.iter().any(|error| message.contains(error))
+        }
+        Some(RejectCode::SysFatal | RejectCode::SysTransient | RejectCode::DestinationInvalid) => {
+            true
+        }
+        Some(RejectCode::CanisterReject | RejectCode::SysUnknown | RejectCode::Unrecognized(_))
+        | None => false,
     }
+}
 
-    // 1) Charge the operator-set fee.
-    PAYMENT_GUARD
-        .deduct(p, config.fee.amount)
-        .await
-        .map_err(map_guard_err)?;
+/// Checks that the fees of a batch, in `denoms`, can be added up and charged
+/// with `payment`: they must all be in the denomination that `payment` pays in.
+fn check_batch_denom<'a>(
+    payment: &PaymentType,
+    denoms: impl IntoIterator<Item = &'a FeeDenom>,
+) -> Result<(), BridgeError> {
+    let paid_in = payment_denom(payment);
+    for denom in denoms {
+        if paid_in.as_ref() != Some(denom) {
+            return Err(BridgeError::FeeDenomMismatch {
+                fee: denom.clone(),
+                payment: paid_in,
+            });
+        }
+    }
+    Ok(())
+}
 
-    // 2) Forward the call with the operator-set cycles.
-    forward_raw(args.target, &args.method, args.args, cycles)
-        .await
-        .map_err(|e| BridgeError::TargetRejected(e).to_string())
+/// The denomination a payment type pays in, if the bridge knows it.
+fn payment_denom(payment: &PaymentType) -> Option<FeeDenom> {
+    if is_cycle_payment(payment) {
+        Some(FeeDenom::Cycles)
+    } else {
+        token_ledger(payment).map(|ledger| FeeDenom::Icrc2 { ledger })
+    }
 }
 
 /// Whether a payment type credits this canister's *cycle* balance (as opposed to
@@ -97,3 +952,422 @@ fn is_cycle_payment(payment: &PaymentType) -> bool {
             | PaymentType::PatronPaysIcrc2Cycles(_)
     )
 }
+
+/// The ledger the payer approves the wrapper to take the fee from, if any.
+fn payment_ledger(payment: &PaymentType) -> Option<Principal> {
+    match payment {
+        PaymentType::CallerPaysIcrc2Cycles | PaymentType::PatronPaysIcrc2Cycles(_) => {
+            Some(cycles_ledger_canister_id())
+        }
+        _ => token_ledger(payment),
+    }
+}
+
+/// The ledger a token payment is made on, if any.
+fn token_ledger(payment: &PaymentType) -> Option<Principal> {
+    match payment {
+        PaymentType::CallerPaysIcrc2Tokens(payment_type) => Some(payment_type.ledger),
+        PaymentType::PatronPaysIcrc2Tokens(payment_type) => Some(payment_type.ledger),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::types::{ForwardCyclesRange, RefundPolicy};
+    use ic_management_canister_types::EcdsaKeyId;
+
+    fn call(target: Principal, method: &str, args: Vec<u8>) -> BridgeCallArgs {
+        BridgeCallArgs {
+            target,
+            method: method.to_string(),
+            args,
+            payment: None,
+            forward_cycles: None,
+            request_id: None,
+            max_fee: None,
+        }
+    }
+
+    /// A store that prices nothing, and buys cycles at one per token.
+    struct OneToOne;
+
+    impl ConfigStore for OneToOne {
+        fn resolve_config(&self, _key: &MethodKey) -> Option<MethodConfig> {
+            None
+        }
+
+        fn begin_request(
+            &self,
+            _key: RequestKey,
+            _target: Principal,
+            _method: &str,
+            _fingerprint: [u8; 32],
+            _now: u64,
+        ) -> Result<Option<Result<BridgeReply, BridgeError>>, BridgeError> {
+            Ok(None)
+        }
+
+        fn complete_request(&self, _key: &RequestKey, _outcome: Result<BridgeReply, BridgeError>) {}
+
+        fn abandon_request(&self, _key: &RequestKey) {}
+
+        fn get_rate(&self, _ledger: &Principal) -> Option<TokenCyclesRate> {
+            Some(TokenCyclesRate {
+                tokens: 1,
+                cycles: 1,
+            })
+        }
+    }
+
+    /// A store that prices nothing, and holds a cycles reserve.
+    struct Reserve(std::cell::Cell<u128>);
+
+    impl ConfigStore for Reserve {
+        fn resolve_config(&self, _key: &MethodKey) -> Option<MethodConfig> {
+            None
+        }
+
+        fn begin_request(
+            &self,
+            _key: RequestKey,
+            _target: Principal,
+            _method: &str,
+            _fingerprint: [u8; 32],
+            _now: u64,
+        ) -> Result<Option<Result<BridgeReply, BridgeError>>, BridgeError> {
+            Ok(None)
+        }
+
+        fn complete_request(&self, _key: &RequestKey, _outcome: Result<BridgeReply, BridgeError>) {}
+
+        fn abandon_request(&self, _key: &RequestKey) {}
+
+        fn reserve_balance(&self) -> u128 {
+            self.0.get()
+        }
+
+        fn take_from_reserve(&self, amount: u128) -> Result<(), u128> {
+            let balance = self.0.get();
+            self.0.set(balance.checked_sub(amount).ok_or(balance)?);
+            Ok(())
+        }
+
+        fn return_to_reserve(&self, amount: u128) {
+            self.0.set(self.0.get() + amount);
+        }
+    }
+
+    /// A guard that accepts every payment.
+    struct Free;
+
+    impl PaymentGuard for Free {
+        fn accepts(&self, _payment: &PaymentType) -> bool {
+            true
+        }
+
+        async fn deduct(&self, _payment: PaymentType, _fee: u128) -> Result<(), PaymentError> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn reply_reserve_does_not_fund_forwarded_cycles() {
+        let bridge = Bridge::new(&OneToOne, &Free);
+        let price = |reply_reserve| Price {
+            config: MethodConfig {
+                forward_cycles: Some(900),
+                ..MethodConfig::cycles(1_000)
+            },
+            payment: PaymentType::CallerPaysIcrc2Tokens(
+                ic_papi_api::caller::CallerPaysIcrc2Tokens {
+                    ledger: Principal::from_slice(&[1]),
+                },
+            ),
+            charge: Charge {
+                fee: 1_000,
+                forward_cycles: 900,
+            },
+            reply_reserve,
+            cache_key: None,
+            cached: None,
+        };
+        assert!(matches!(
+            bridge.funding(&price(100)),
+            Ok(CycleFunding::Reserve(_))
+        ));
+        assert!(matches!(
+            bridge.funding(&price(200)),
+            Err(BridgeError::FeeDoesNotCoverForwardedCycles {
+                fee_in_cycles: 800,
+                forward_cycles: 900,
+            })
+        ));
+    }
+
+    #[test]
+    fn caller_chosen_cycles_must_be_in_range() {
+        let bridge = Bridge::new(&OneToOne, &Free);
+        let config = MethodConfig {
+            forward_range: Some(ForwardCyclesRange {
+                min: 1_000,
+                max: 10_000,
+                markup_bps: 100,
+            }),
+            ..MethodConfig::cycles(100)
+        };
+        let price = |forward_cycles| {
+            config.charge(forward_cycles).map(|charge| Price {
+                config: config.clone(),
+                payment: PaymentType::CallerPaysIcrc2Tokens(
+                    ic_papi_api::caller::CallerPaysIcrc2Tokens {
+                        ledger: Principal::from_slice(&[1]),
+                    },
+                ),
+                charge,
+                reply_reserve: 0,
+                cache_key: None,
+                cached: None,
+            })
+        };
+        let chosen = price(Some(5_000)).expect("the amount is within the range");
+        assert_eq!(
+            chosen.charge,
+            Charge {
+                fee: 100 + 5_000 + 50,
+                forward_cycles: 5_000,
+            }
+        );
+        // The fee, at one cycle per token, funds the chosen cycles.
+        assert!(matches!(
+            bridge.funding(&chosen),
+            Ok(CycleFunding::Reserve(_))
+        ));
+        for forward_cycles in [999, 10_001] {
+            assert!(matches!(
+                price(Some(forward_cycles)),
+                Err(BridgeError::ForwardCyclesOutOfRange { .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn reserve_funds_token_calls_until_it_runs_out() {
+        let store = Reserve(std::cell::Cell::new(1_500));
+        let bridge = Bridge::new(&store, &Free);
+        let reserve = CycleFunding::Reserve(TokenCyclesRate {
+            tokens: 1,
+            cycles: 1,
+        });
+        assert_eq!(bridge.set_aside(&reserve, 1_000), Ok(()));
+        assert_eq!(
+            bridge.set_aside(&reserve, 1_000),
+            Err(BridgeError::InsufficientCyclesReserve {
+                needed: 1_000,
+                available: 500,
+            })
+        );
+        // Calls paid in cycles fund their own forwarding.
+        assert_eq!(bridge.set_aside(&CycleFunding::Payment, 1_000), Ok(()));
+        assert_eq!(store.reserve_balance(), 500);
+    }
+
+    #[test]
+    #[cfg(feature = "canister")]
+    fn charged_calls_are_replayed_on_retry() {
+        use crate::store::StateStore;
+
+        let bridge = Bridge::new(&StateStore, &Free);
+        let target = Principal::from_slice(&[5]);
+        let args = BridgeCallArgs {
+            request_id: Some("r".to_string()),
+            ..call(target, "m", vec![1])
+        };
+        let key = (Principal::from_slice(&[6]), "r".to_string());
+        let begin = |args: &BridgeCallArgs, now| {
+            StateStore.begin_request(key.clone(), target, "m", args.fingerprint(), now)
+        };
+        assert_eq!(begin(&args, 0), Ok(None));
+        // Nothing was charged, so the retry goes ahead.
+        bridge.settle_request(&key, &ProxyOutcome::NotCharged(BridgeError::SelfCall));
+        assert_eq!(begin(&args, 1), Ok(None));
+        let reply = Ok(BridgeReply {
+            reply: vec![7].into(),
+            cycles_forwarded: 0,
+            cycles_unused: 0,
+            cycles_unused_error: None,
+            reply_reserve_returned: 0,
+            reply_reserve_error: None,
+            from_cache: false,
+        });
+        bridge.settle_request(&key, &ProxyOutcome::Charged(reply.clone()));
+        assert_eq!(begin(&args, 2), Ok(Some(reply)));
+        // A retry that asks for something else is refused, not replayed.
+        let other = BridgeCallArgs {
+            args: vec![2],
+            ..args.clone()
+        };
+        assert_eq!(
+            begin(&other, 3),
+            Err(BridgeError::RequestIdReusedWithOtherArgs)
+        );
+    }
+
+    #[test]
+    fn rejected_calls_are_refunded_by_policy() {
+        let refunded = |refund, fee, reply_reserve| {
+            let config = MethodConfig {
+                refund,
+                ..MethodConfig::cycles(fee)
+            };
+            rejected_call_refund(&config, fee, reply_reserve, 0)
+        };
+        let minus = |processing_fee| Some(RefundPolicy::MinusProcessingFee { processing_fee });
+        assert_eq!(refunded(None, 1_000, 0), 0);
+        assert_eq!(refunded(Some(RefundPolicy::NoRefund), 1_000, 0), 0);
+        assert_eq!(refunded(Some(RefundPolicy::Full), 1_000, 0), 1_000);
+        assert_eq!(refunded(minus(150), 1_000, 0), 850);
+        assert_eq!(refunded(minus(1_500), 1_000, 0), 0);
+        // The reply reservation is returned whatever the policy, which applies
+        // to the rest of the fee.
+        assert_eq!(refunded(None, 1_200, 200), 200);
+        assert_eq!(refunded(minus(150), 1_200, 200), 1_050);
+    }
+
+    #[test]
+    fn cycles_the_target_did_not_accept_are_returned() {
+        // The target accepted 400 of 1_000 forwarded cycles.
+        let rate = TokenCyclesRate {
+            tokens: 1,
+            cycles: 10,
+        };
+        assert_eq!(CycleFunding::Payment.payers_share(600), 600);
+        assert_eq!(CycleFunding::Payment.cost_of(400), 400);
+        assert_eq!(CycleFunding::Reserve(rate).payers_share(600), 0);
+        assert_eq!(CycleFunding::Reserve(rate).cost_of(400), 40);
+        // If it then rejected the call, only what it kept is not refunded.
+        let config = MethodConfig {
+            refund: Some(RefundPolicy::Full),
+            forward_cycles: Some(1_000),
+            ..MethodConfig::cycles(1_500)
+        };
+        assert_eq!(rejected_call_refund(&config, 1_500, 0, 400), 1_100);
+        assert_eq!(rejected_call_refund(&config, 1_500, 0, 0), 1_500);
+    }
+
+    #[test]
+    fn refunds_must_be_worth_the_ledger_fee() {
+        let bridge = Bridge::new(&OneToOne, &Free);
+        assert_eq!(
+            bridge.min_refund(&PaymentType::AttachedCycles),
+            crate::domain::types::CYCLES_LEDGER_FEE
+        );
+        // The fee of a token ledger is unknown to this store.
+        let tokens =
+            PaymentType::CallerPaysIcrc2Tokens(ic_papi_api::caller::CallerPaysIcrc2Tokens {
+                ledger: Principal::from_slice(&[1]),
+            });
+        assert_eq!(bridge.min_refund(&tokens), 0);
+    }
+
+    #[test]
+    fn keys_are_derived_per_caller() {
+        let caller = Principal::from_slice(&[7]);
+        let sign = SignWithEcdsaArgs {
+            message_hash: vec![0; 32],
+            derivation_path: vec![b"wallet".to_vec()],
+            key_id: EcdsaKeyId::default(),
+        };
+        let mut args = call(
+            MANAGEMENT_CANISTER_ID,
+            "sign_with_ecdsa",
+            encode_one(&sign).unwrap(),
+        );
+        scope_keys_to_caller(caller, &mut args).unwrap();
+        let scoped: SignWithEcdsaArgs = decode_one(&args.args).unwrap();
+        assert_eq!(
+            scoped.derivation_path,
+            vec![caller.as_slice().to_vec(), b"wallet".to_vec()]
+        );
+        assert_eq!(scoped.message_hash, sign.message_hash);
+    }
+
+    #[test]
+    fn batch_fees_must_share_the_payment_denom() {
+        let ledger = Principal::from_slice(&[1]);
+        let tokens = FeeDenom::Icrc2 { ledger };
+        let token_payment =
+            PaymentType::CallerPaysIcrc2Tokens(ic_papi_api::caller::CallerPaysIcrc2Tokens {
+                ledger,
+            });
+        assert_eq!(
+            check_batch_denom(
+                &PaymentType::AttachedCycles,
+                [&FeeDenom::Cycles, &FeeDenom::Cycles]
+            ),
+            Ok(())
+        );
+        assert_eq!(
+            check_batch_denom(&token_payment, [&tokens, &tokens]),
+            Ok(())
+        );
+        assert_eq!(
+            check_batch_denom(&token_payment, [&tokens, &FeeDenom::Cycles]),
+            Err(BridgeError::FeeDenomMismatch {
+                fee: FeeDenom::Cycles,
+                payment: Some(tokens.clone()),
+            })
+        );
+        assert_eq!(
+            check_batch_denom(&PaymentType::CallerPaysIcrc2Cycles, [&tokens]),
+            Err(BridgeError::FeeDenomMismatch {
+                fee: tokens,
+                payment: Some(FeeDenom::Cycles),
+            })
+        );
+    }
+
+    #[test]
+    fn only_target_failures_trip_the_breaker() {
+        let reject = |code, message: &str| TargetReject {
+            reject_code: code,
+            message: message.to_string(),
+            may_have_executed: true,
+        };
+        assert!(is_target_failure(&reject(
+            Some(RejectCode::CanisterError),
+            "Canister trapped: heap out of bounds"
+        )));
+        assert!(is_target_failure(&reject(
+            Some(RejectCode::SysTransient),
+            "Canister is stopped"
+        )));
+        assert!(!is_target_failure(&reject(
+            Some(RejectCode::CanisterError),
+            "Panicked at 'Fail to decode argument 0 from table0 to text'"
+        )));
+        assert!(!is_target_failure(&reject(
+            Some(RejectCode::CanisterReject),
+            "not allowed"
+        )));
+        assert!(!is_target_failure(&reject(
+            Some(RejectCode::SysUnknown),
+            "timeout"
+        )));
+        assert!(!is_target_failure(&reject(None, "insufficient cycles")));
+    }
+
+    #[test]
+    fn other_calls_are_left_alone() {
+        let caller = Principal::from_slice(&[7]);
+        let mut other = call(Principal::from_slice(&[1]), "sign_with_ecdsa", vec![1, 2]);
+        scope_keys_to_caller(caller, &mut other).unwrap();
+        assert_eq!(other.args, vec![1, 2]);
+        let mut garbled = call(MANAGEMENT_CANISTER_ID, "schnorr_public_key", vec![1, 2]);
+        assert!(matches!(
+            scope_keys_to_caller(caller, &mut garbled),
+            Err(BridgeError::Candid(_))
+        ));
+    }
+}
@@ -0,0 +1,286 @@
+//! Bulk export and import of the price registry.
+
+use candid::Principal;
+use std::collections::HashMap;
+use std::hash::Hash;
+
+use crate::api::validate::check_method_target;
+use crate::canister::{check_rule_target, validate_config};
+use crate::domain::types::{
+    ConfigExport, ConfigKey, ImportConfigArgs, ImportMode, ImportSummary, MethodConfig,
+    PricingRule, CONFIG_EXPORT_VERSION,
+};
+use crate::state;
+
+/// Snapshot of every price and pricing rule.
+#[must_use]
+pub fn export() -> ConfigExport {
+    ConfigExport::new(state::list_configs(), state::list_rules())
+}
+
+/// Validates an import and, unless it is a dry run, applies it.
+///
+/// # Errors
+/// If the export has an unknown version or a wrong hash, contains the same key
+/// twice, or any entry fails validation. Nothing is changed in that case.
+pub fn import(actor: Principal, now: u64, args: ImportConfigArgs) -> Result<ImportSummary, String> {
+    let ImportConfigArgs {
+        config,
+        mode,
+        dry_run,
+    } = args;
+    if config.version != CONFIG_EXPORT_VERSION {
+        return Err(format!(
+            "Unsupported export version {}; this wrapper reads version {CONFIG_EXPORT_VERSION}.",
+            config.version
+        ));
+    }
+    if config.hash.as_slice() != config.compute_hash().as_slice() {
+        return Err("The export's hash does not match its contents.".to_string());
+    }
+
+    for (key, method_config) in &config.methods {
+        check_method_target(key, method_config)
+            .and_then(|()| validate_config(method_config))
+            .map_err(|e| format!("Method `{}` on `{}`: {e}", key.method, key.target))?;
+    }
+    for (rule, method_config) in &config.rules {
+        check_rule_target(rule)
+            .and_then(|()| validate_config(method_config))
+            .map_err(|e| {
+                format!(
+                    "Rule for prefix `{}` on `{}`: {e}",
+                    rule.method_prefix, rule.target
+                )
+            })?;
+    }
+
+    let mut summary = ImportSummary::default();
+    let method_changes = plan(state::list_configs(), config.methods, mode, &mut summary)
+        .map_err(|key| format!("Method `{}` on `{}` appears twice.", key.method, key.target))?;
+    let rule_changes =
+        plan(state::list_rules(), config.rules, mode, &mut summary).map_err(|rule| {
+            format!(
+                "Rule for prefix `{}` on `{}` appears twice.",
+                rule.method_prefix, rule.target
+            )
+        })?;
+    let methods = apply(state::list_configs(), &method_changes);
+    let rules = apply(state::list_rules(), &rule_changes);
+
+    // Either every call to a target is wrapped in a caller envelope or none
+    // is, counting the changes still scheduled for entries the import leaves
+    // alone.
+    let mut envelopes: HashMap<Principal, bool> = HashMap::new();
+    let pending_methods = state::list_pending_configs()
+        .into_iter()
+        .filter(|(key, _)| !method_changes.iter().any(|(changed, _, _)| changed == key))
+        .filter_map(|(key, pending)| Some((key.target, pending.config?)));
+    let pending_rules = state::list_pending_rules()
+        .into_iter()
+        .filter(|(rule, _)| !rule_changes.iter().any(|(changed, _, _)| changed == rule))
+        .filter_map(|(rule, pending)| Some((rule.target, pending.config?)));
+    for (target, config) in methods
+        .iter()
+        .map(|(key, config)| (key.target, config.clone()))
+        .chain(
+            rules
+                .iter()
+                .map(|(rule, config)| (rule.target, config.clone())),
+        )
+        .chain(pending_methods)
+        .chain(pending_rules)
+    {
+        if *envelopes
+            .entry(target)
+            .or_insert_with(|| config.forwards_caller())
+            != config.forwards_caller()
+        {
+            return Err(format!(
+                "Canister `{target}`: every price on it must set forward_caller alike."
+            ));
+        }
+    }
+
+    // An import may not raise the price callers pay without notice; such
+    // changes must be scheduled with `set_method_config`, `set_pricing_rule`
+    // or their removals.
+    if state::get_min_increase_notice() > 0 {
+        let rule_price = |filter: &dyn Fn(&PricingRule) -> bool| {
+            rules
+                .iter()
+                .filter(|(rule, _)| filter(rule))
+                .max_by_key(|(rule, _)| rule.method_prefix.len())
+                .map(|(_, config)| config.clone())
+        };
+        for (key, _, new) in &method_changes {
+            let new = new
+                .clone()
+                .or_else(|| rule_price(&|rule| rule.matches(key)));
+            if MethodConfig::change_raises_price(state::resolve_config(key).as_ref(), new.as_ref())
+            {
+                return Err(format!(
+                    "Method `{}` on `{}`: the import raises its price, which must be \
+                     scheduled with set_method_config or remove_method_config.",
+                    key.method, key.target
+                ));
+            }
+        }
+        for (changed, _, _) in &rule_changes {
+            let new = rule_price(&|rule| {
+                rule.target == changed.target
+                    && changed.method_prefix.starts_with(&rule.method_prefix)
+            });
+            if MethodConfig::change_raises_price(state::rule_price(changed).as_ref(), new.as_ref())
+            {
+                return Err(format!(
+                    "Rule for prefix `{}` on `{}`: the import raises its price, which must be \
+                     scheduled with set_pricing_rule or remove_pricing_rule.",
+                    changed.method_prefix, changed.target
+                ));
+            }
+        }
+    }
+    if dry_run {
+        return Ok(summary);
+    }
+
+    state::apply_due_configs(now);
+    for (key, old, new) in method_changes {
+        let _ = state::cancel_pending_config(&key);
+        state::clear_cached_replies(&key);
+        match &new {
+            Some(config) => state::set_config(key.clone(), config.clone()),
+            None => state::remove_config(&key),
+        };
+        state::record_config_change(actor, now, ConfigKey::Method(key), old, new, None);
+    }
+    for (rule, old, new) in rule_changes {
+        let _ = state::cancel_pending_rule(&rule);
+        state::clear_cached_replies_for_rule(&rule);
+        match &new {
+            Some(config) => state::set_rule(rule.clone(), config.clone()),
+            None => state::remove_rule(&rule),
+        };
+        state::record_config_change(actor, now, ConfigKey::Rule(rule), old, new, None);
+    }
+    Ok(summary)
+}
+
+/// The entries left once `changes` are applied to `current`.
+fn apply<K: Eq + Hash + Clone>(
+    current: Vec<(K, MethodConfig)>,
+    changes: &[Change<K>],
+) -> HashMap<K, MethodConfig> {
+    let mut entries: HashMap<K, MethodConfig> = current.into_iter().collect();
+    for (key, _, new) in changes {
+        match new {
+            Some(config) => entries.insert(key.clone(), config.clone()),
+            None => entries.remove(key),
+        };
+    }
+    entries
+}
+
+/// A change to one entry: its key, old value and new value.
+type Change<K> = (K, Option<MethodConfig>, Option<MethodConfig>);
+
+/// Works out which entries an import changes, and counts them in `summary`.
+///
+/// # Errors
+/// Returns a key that appears more than once in `incoming`.
+fn plan<K: Eq + Hash + Clone>(
+    current: Vec<(K, MethodConfig)>,
+    incoming: Vec<(K, MethodConfig)>,
+    mode: ImportMode,
+    summary: &mut ImportSummary,
+) -> Result<Vec<Change<K>>, K> {
+    let mut current: HashMap<K, MethodConfig> = current.into_iter().collect();
+    let mut changes = Vec::new();
+    let mut seen = HashMap::with_capacity(incoming.len());
+    for (key, config) in incoming {
+        if seen.insert(key.clone(), ()).is_some() {
+            return Err(key);
+        }
+        match current.remove(&key) {
+            Some(old) if old == config => summary.unchanged += 1,
+            Some(old) => {
+                summary.updated += 1;
+                changes.push((key, Some(old), Some(config)));
+            }
+            None => {
+                summary.added += 1;
+                changes.push((key, None, Some(config)));
+            }
+        }
+    }
+    // Whatever is left was not in the import.
+    for (key, old) in current {
+        match mode {
+            ImportMode::Replace => {
+                summary.removed += 1;
+                changes.push((key, Some(old), None));
+            }
+            ImportMode::Merge => summary.unchanged += 1,
+        }
+    }
+    Ok(changes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plan_merges_or_replaces() {
+        let current = vec![
+            ("a", MethodConfig::cycles(1)),
+            ("b", MethodConfig::cycles(2)),
+            ("c", MethodConfig::cycles(3)),
+        ];
+        let incoming = vec![
+            ("a", MethodConfig::cycles(1)),
+            ("b", MethodConfig::cycles(20)),
+            ("d", MethodConfig::cycles(4)),
+        ];
+
+        let mut summary = ImportSummary::default();
+        let changes = plan(
+            current.clone(),
+            incoming.clone(),
+            ImportMode::Merge,
+            &mut summary,
+        )
+        .expect("no duplicates");
+        assert_eq!(
+            summary,
+            ImportSummary {
+                added: 1,
+                updated: 1,
+                removed: 0,
+                unchanged: 2,
+            }
+        );
+        assert_eq!(changes.len(), 2);
+
+        let mut summary = ImportSummary::default();
+        let changes =
+            plan(current, incoming, ImportMode::Replace, &mut summary).expect("no duplicates");
+        assert_eq!(summary.removed, 1);
+        assert_eq!(summary.unchanged, 1);
+        assert!(changes.contains(&("c", Some(MethodConfig::cycles(3)), None)));
+    }
+
+    #[test]
+    fn plan_rejects_duplicate_keys() {
+        let mut summary = ImportSummary::default();
+        let incoming = vec![
+            ("a", MethodConfig::cycles(1)),
+            ("a", MethodConfig::cycles(2)),
+        ];
+        assert_eq!(
+            plan(vec![], incoming, ImportMode::Merge, &mut summary),
+            Err("a")
+        );
+    }
+}
@@ -1 +1,6 @@
 pub mod call;
+#[cfg(feature = "canister")]
+pub mod config;
+#[cfg(feature = "canister")]
+pub mod owners;
+pub mod validate;
@@ -0,0 +1,55 @@
+//! Self-service registration of targets by their owners, and owner payouts.
+
+use candid::Principal;
+use ic_papi_api::Account;
+
+use crate::domain::types::FeeDenom;
+use crate::payments::payout::pay_out;
+use crate::state;
+use crate::util::management::canister_info;
+
+/// Records that `caller` asks to own `target`, after checking with the
+/// management canister that `caller` controls it. The claim only takes effect
+/// once an admin approves it, since the owner earns the revenue of prices the
+/// operator may already have set. Returns any claim it replaces.
+///
+/// # Errors
+/// If `target` is the wrapper or the management canister, if its controllers
+/// cannot be looked up, or if `caller` is not one of them.
+pub async fn register(caller: Principal, target: Principal) -> Result<Option<Principal>, String> {
+    if target == ic_cdk::api::canister_self() || target == Principal::management_canister() {
+        return Err(format!("Canister `{target}` cannot be registered."));
+    }
+    let info = canister_info(target).await?;
+    if !info.controllers.contains(&caller) {
+        return Err(format!(
+            "Only a controller of canister `{target}` may register it."
+        ));
+    }
+    Ok(state::claim_target(target, caller))
+}
+
+/// Pays `amount` out of `owner`'s balance in `denom` to `to`, returning what
+/// `to` receives.
+///
+/// The balance is debited before the transfer, so that concurrent withdrawals
+/// cannot overdraw it, and credited back if the transfer fails.
+///
+/// # Errors
+/// If the balance is too low, or the payout fails.
+pub async fn withdraw(
+    owner: Principal,
+    denom: FeeDenom,
+    amount: u128,
+    to: Account,
+) -> Result<u128, String> {
+    state::debit_owner(owner, denom.clone(), amount)
+        .map_err(|balance| format!("Cannot withdraw {amount}: the balance is {balance}."))?;
+    match pay_out(&denom, to, amount).await {
+        Ok(received) => Ok(received),
+        Err(e) => {
+            state::add_to_owner_balance(owner, denom, amount);
+            Err(e)
+        }
+    }
+}
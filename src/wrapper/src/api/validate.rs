@@ -0,0 +1,328 @@
+//! Validation of method configurations, shared by the wrapper canister and
+//! [`Bridge`](crate::api::call::Bridge), which checks every configuration its
+//! store resolves before charging for a call.
+
+use candid::Principal;
+
+use crate::api::call::check_target;
+use crate::domain::types::{
+    CallWait, FeeDenom, MethodConfig, MethodKey, TokenCyclesRate, DEFAULT_CALL_TIMEOUT_SECONDS,
+};
+
+/// Reject configurations that would let the wrapper forward more cycles than the
+/// fee funds. When a method forwards cycles, the fee must be at least the
+/// forwarded amount: either denominated in cycles, or in a token whose ledger
+/// has a token-to-cycles rate, in which case the reserve funds the cycles.
+///
+/// `rate_of` gives the token-to-cycles rate of a ledger, if it has one.
+///
+/// # Errors
+/// Describes the first problem found.
+pub fn validate_config(
+    config: &MethodConfig,
+    rate_of: impl Fn(&Principal) -> Option<TokenCyclesRate>,
+) -> Result<(), String> {
+    if let Some(CallWait::Bounded {
+        timeout_seconds: Some(timeout),
+    }) = config.wait
+    {
+        if timeout == 0 || timeout > DEFAULT_CALL_TIMEOUT_SECONDS {
+            return Err(format!(
+                "wait.timeout_seconds must be between 1 and {DEFAULT_CALL_TIMEOUT_SECONDS}."
+            ));
+        }
+    }
+    if let Some(cache) = &config.cache {
+        if config.forwards_caller() {
+            return Err(
+                "A method that receives the caller's identity may not be cached.".to_string(),
+            );
+        }
+        if cache.ttl_seconds == 0 {
+            return Err("cache.ttl_seconds must be greater than zero.".to_string());
+        }
+        if cache.hit_fee > config.fee.amount {
+            return Err(format!(
+                "cache.hit_fee ({}) must not exceed the fee ({}).",
+                cache.hit_fee, config.fee.amount
+            ));
+        }
+    }
+    if let Some(size) = &config.size_pricing {
+        if size.per_reply_byte > 0 && size.max_reply_bytes == 0 {
+            return Err(
+                "size_pricing.max_reply_bytes must be set to charge for reply bytes.".to_string(),
+            );
+        }
+        size.charge(0).map_err(|e| e.to_string())?;
+    }
+    let charges = match &config.forward_range {
+        Some(range) => {
+            if config.forward_cycles.is_some_and(|forward| forward > 0) {
+                return Err("Set either forward_cycles or forward_range, not both.".to_string());
+            }
+            if range.min > range.max {
+                return Err(format!(
+                    "forward_range.min ({}) must not exceed forward_range.max ({}).",
+                    range.min, range.max
+                ));
+            }
+            // The fee grows linearly with the forwarded amount, so if both ends
+            // of the range are covered, so is everything in between.
+            vec![
+                config.charge(Some(range.min)),
+                config.charge(Some(range.max)),
+            ]
+        }
+        None => vec![config.charge(None)],
+    };
+    for charge in charges {
+        let charge = charge.map_err(|e| e.to_string())?;
+        let forward = charge.forward_cycles;
+        if forward == 0 {
+            continue;
+        }
+        match &config.fee.denom {
+            FeeDenom::Cycles => {
+                if charge.fee < forward {
+                    return Err(format!(
+                        "The fee ({}) must cover the cycles to forward ({forward}).",
+                        charge.fee
+                    ));
+                }
+            }
+            FeeDenom::Icrc2 { ledger } => {
+                let rate = rate_of(ledger).ok_or_else(|| {
+                    format!(
+                        "A method that forwards cycles must charge its fee in cycles (fee.denom = Cycles), \
+                         or in a token with a token-to-cycles rate; none is set for ledger {ledger}."
+                    )
+                })?;
+                let fee_in_cycles = rate.to_cycles(charge.fee);
+                if fee_in_cycles < forward {
+                    return Err(format!(
+                        "The fee ({}, worth {fee_in_cycles} cycles) must cover the cycles to forward ({forward}).",
+                        charge.fee
+                    ));
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Whether `config` may price `key`: the method must be reachable through the
+/// bridge, and management canister methods must be priced as
+/// [`check_management_config`] requires.
+///
+/// # Errors
+/// Describes why `key` may not be priced with `config`.
+pub fn check_method_target(key: &MethodKey, config: &MethodConfig) -> Result<(), String> {
+    check_target(key.target, &key.method).map_err(|e| e.to_string())?;
+    if key.target == Principal::management_canister() {
+        check_management_config(config)?;
+    }
+    Ok(())
+}
+
+/// The management canister cannot receive a caller envelope, and its replies
+/// may not be cached: they are random, like `raw_rand`, or derived for the
+/// caller.
+fn check_management_config(config: &MethodConfig) -> Result<(), String> {
+    if config.forwards_caller() {
+        return Err(
+            "Management canister methods cannot receive the caller's identity.".to_string(),
+        );
+    }
+    if config.cache.is_some() {
+        return Err(
+            "Replies of management canister methods cannot be cached: they are random or \
+             derived for the caller."
+                .to_string(),
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::types::{CacheConfig, FeeSpec, ForwardCyclesRange};
+
+    fn config(fee_amount: u128, denom: FeeDenom, forward: Option<u128>) -> MethodConfig {
+        MethodConfig {
+            fee: FeeSpec {
+                amount: fee_amount,
+                denom,
+            },
+            forward_cycles: forward,
+            ..MethodConfig::cycles(0)
+        }
+    }
+
+    /// Validates `config` where no ledger has a token-to-cycles rate.
+    fn validate(config: &MethodConfig) -> Result<(), String> {
+        validate_config(config, |_| None)
+    }
+
+    #[test]
+    fn accepts_config_without_forwarding() {
+        // Any fee denomination is fine when no cycles are forwarded.
+        assert!(validate(&config(0, FeeDenom::Cycles, None)).is_ok());
+        assert!(validate(&config(5, FeeDenom::Cycles, Some(0))).is_ok());
+        let ledger = Principal::anonymous();
+        assert!(validate(&config(5, FeeDenom::Icrc2 { ledger }, None)).is_ok());
+    }
+
+    #[test]
+    fn accepts_forwarding_covered_by_cycle_fee() {
+        assert!(validate(&config(1000, FeeDenom::Cycles, Some(1000))).is_ok());
+        assert!(validate(&config(2000, FeeDenom::Cycles, Some(1000))).is_ok());
+    }
+
+    #[test]
+    fn rejects_forwarding_with_token_fee() {
+        let ledger = Principal::anonymous();
+        let err = validate(&config(1000, FeeDenom::Icrc2 { ledger }, Some(1000)))
+            .expect_err("token-denominated fee cannot fund forwarded cycles");
+        assert!(err.contains("cycles"), "unexpected error: {err}");
+    }
+
+    #[test]
+    fn rejects_forwarding_exceeding_fee() {
+        let err = validate(&config(999, FeeDenom::Cycles, Some(1000)))
+            .expect_err("fee below forwarded amount must be rejected");
+        assert!(err.contains("cover"), "unexpected error: {err}");
+    }
+
+    fn ranged(denom: FeeDenom, min: u128, max: u128) -> MethodConfig {
+        MethodConfig {
+            forward_range: Some(ForwardCyclesRange {
+                min,
+                max,
+                markup_bps: 100,
+            }),
+            ..config(1000, denom, None)
+        }
+    }
+
+    #[test]
+    fn accepts_forward_range_with_cycle_fee() {
+        assert!(validate(&ranged(FeeDenom::Cycles, 0, 1_000_000)).is_ok());
+    }
+
+    #[test]
+    fn rejects_forward_range_with_token_fee() {
+        let ledger = Principal::anonymous();
+        let err = validate(&ranged(FeeDenom::Icrc2 { ledger }, 0, 1000))
+            .expect_err("token-denominated fee cannot fund forwarded cycles");
+        assert!(err.contains("cycles"), "unexpected error: {err}");
+    }
+
+    #[test]
+    fn rejects_inverted_forward_range() {
+        let err = validate(&ranged(FeeDenom::Cycles, 1001, 1000))
+            .expect_err("min above max must be rejected");
+        assert!(err.contains("must not exceed"), "unexpected error: {err}");
+    }
+
+    #[test]
+    fn rejects_forward_range_with_fixed_forwarding() {
+        let config = MethodConfig {
+            forward_cycles: Some(10),
+            ..ranged(FeeDenom::Cycles, 0, 1000)
+        };
+        let err = validate(&config).expect_err("both forwarding modes must be rejected");
+        assert!(err.contains("not both"), "unexpected error: {err}");
+    }
+
+    #[test]
+    fn rejects_forward_range_whose_price_overflows() {
+        let err = validate(&ranged(FeeDenom::Cycles, 0, u128::MAX))
+            .expect_err("an unrepresentable price must be rejected");
+        assert!(err.contains("too large"), "unexpected error: {err}");
+    }
+
+    #[test]
+    fn accepts_forwarding_with_token_fee_covered_at_rate() {
+        let ledger = Principal::from_slice(&[1]);
+        let rate = |_: &Principal| {
+            Some(TokenCyclesRate {
+                tokens: 1,
+                cycles: 1_000,
+            })
+        };
+        let token = |forward| config(1, FeeDenom::Icrc2 { ledger }, Some(forward));
+        assert!(validate_config(&token(1_000), rate).is_ok());
+        let err = validate_config(&token(1_001), rate)
+            .expect_err("a token fee worth less than the forwarded cycles must be rejected");
+        assert!(err.contains("cover"), "unexpected error: {err}");
+        // Ranges are checked at both ends.
+        assert!(validate_config(&ranged(FeeDenom::Icrc2 { ledger }, 0, 1_000_000), rate).is_ok());
+    }
+
+    #[test]
+    fn validates_call_timeout() {
+        let bounded = |timeout_seconds| MethodConfig {
+            wait: Some(CallWait::Bounded { timeout_seconds }),
+            ..config(1000, FeeDenom::Cycles, None)
+        };
+        assert!(validate(&bounded(None)).is_ok());
+        assert!(validate(&bounded(Some(1))).is_ok());
+        assert!(validate(&bounded(Some(DEFAULT_CALL_TIMEOUT_SECONDS))).is_ok());
+        for timeout in [0, DEFAULT_CALL_TIMEOUT_SECONDS + 1] {
+            let err = validate(&bounded(Some(timeout)))
+                .expect_err("a timeout outside the system's range must be rejected");
+            assert!(err.contains("timeout_seconds"), "unexpected error: {err}");
+        }
+        let unbounded = MethodConfig {
+            wait: Some(CallWait::Unbounded),
+            ..config(1000, FeeDenom::Cycles, None)
+        };
+        assert!(validate(&unbounded).is_ok());
+    }
+
+    #[test]
+    fn validates_cache_config() {
+        let cached = |ttl_seconds, hit_fee| MethodConfig {
+            cache: Some(CacheConfig {
+                ttl_seconds,
+                hit_fee,
+            }),
+            ..config(1000, FeeDenom::Cycles, None)
+        };
+        assert!(validate(&cached(60, 100)).is_ok());
+        assert!(validate(&cached(60, 1000)).is_ok());
+        let err = validate(&cached(0, 100)).expect_err("a zero TTL must be rejected");
+        assert!(err.contains("ttl_seconds"), "unexpected error: {err}");
+        let err =
+            validate(&cached(60, 1001)).expect_err("a cache hit must not cost more than a miss");
+        assert!(err.contains("hit_fee"), "unexpected error: {err}");
+        let err = validate(&MethodConfig {
+            forward_caller: Some(true),
+            ..cached(60, 100)
+        })
+        .expect_err("replies that depend on the caller must not be cached");
+        assert!(err.contains("caller"), "unexpected error: {err}");
+    }
+
+    #[test]
+    fn management_replies_are_not_cached() {
+        assert!(check_management_config(&MethodConfig::cycles(1000)).is_ok());
+        let err = check_management_config(&MethodConfig {
+            cache: Some(CacheConfig {
+                ttl_seconds: 60,
+                hit_fee: 100,
+            }),
+            ..MethodConfig::cycles(1000)
+        })
+        .expect_err("random replies must not be cached");
+        assert!(err.contains("cached"), "unexpected error: {err}");
+        let err = check_management_config(&MethodConfig {
+            forward_caller: Some(true),
+            ..MethodConfig::cycles(1000)
+        })
+        .expect_err("the management canister cannot unwrap an envelope");
+        assert!(err.contains("identity"), "unexpected error: {err}");
+    }
+}
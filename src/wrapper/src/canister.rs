@@ -0,0 +1,1021 @@
+//! The endpoints of the standalone wrapper canister.
+
+use candid::Principal;
+use ic_cdk::api::{canister_cycle_balance, is_controller, msg_caller};
+use ic_cdk::export_candid;
+use ic_cdk::{post_upgrade, query, update};
+use ic_papi_api::{Account, PaymentType};
+use ic_papi_guard::guards::any::PaymentGuard;
+use serde_bytes::ByteBuf;
+
+use crate::api::call::{Bridge, PRICEABLE_MANAGEMENT_METHODS};
+use crate::api::validate::{self, check_method_target};
+use crate::api::{config, owners};
+use crate::domain::errors::BridgeError;
+use crate::domain::types::{
+    BridgeCallArgs, BridgeReply, Call0Args, CallBatchArgs, CallBlobArgs, CallTextArgs, CallWait,
+    CircuitBreakerConfig, CircuitBreakerStatus, ConfigChangePage, ConfigExport, ConfigKey,
+    CostEstimate, CyclesReserve, FeeDenom, ImportConfigArgs, ImportSummary, MethodConfig,
+    MethodKey, MethodPrices, ModulePin, Pauses, PendingConfig, PricingRule, Role, TokenCyclesRate,
+};
+use crate::payments::guard_config::PAYMENT_GUARD;
+use crate::state;
+use crate::store::StateStore;
+
+/// The bridge over the wrapper's own state and payment guard.
+fn bridge() -> Bridge<'static, StateStore, PaymentGuard<5>> {
+    Bridge::new(&StateStore, &PAYMENT_GUARD)
+}
+
+/// Proxies a call to a target method that takes **no arguments**.
+#[update]
+pub async fn call0(args: Call0Args) -> Result<BridgeReply, BridgeError> {
+    bridge().call(msg_caller(), args.into()).await
+}
+
+/// Proxies a call using a **Candid-encoded argument blob**.
+#[update]
+pub async fn call_blob(args: CallBlobArgs) -> Result<BridgeReply, BridgeError> {
+    bridge().call(msg_caller(), args.into()).await
+}
+
+/// Proxies several calls, charging once for the sum of their prices.
+#[update]
+pub async fn call_batch(
+    args: CallBatchArgs,
+) -> Result<Vec<Result<BridgeReply, BridgeError>>, BridgeError> {
+    bridge().call_batch(msg_caller(), args).await
+}
+
+/// Proxies a call using **Candid text** (currently disabled).
+#[update]
+#[allow(clippy::needless_pass_by_value)]
+pub fn call_text(args: CallTextArgs) -> Result<BridgeReply, BridgeError> {
+    let _args: BridgeCallArgs = args.into();
+
+    Err(BridgeError::CallTextDisabled)
+}
+
+/// What a call to `(target, method)` would cost with `payment` (by default the
+/// caller's payment preference, or else attached cycles), including the ledger
+/// fee and the allowance to approve.
+///
+/// Methods with a forwarded-cycles range are priced at `forward_cycles`, or at
+/// the bottom of the range. Methods priced by size are priced for arguments of
+/// `request_bytes`, or of none.
+#[query]
+pub fn estimate_cost(
+    target: Principal,
+    method: String,
+    payment: Option<PaymentType>,
+    forward_cycles: Option<u128>,
+    request_bytes: Option<u64>,
+) -> Result<CostEstimate, BridgeError> {
+    bridge().estimate_cost(
+        msg_caller(),
+        BridgeCallArgs {
+            target,
+            method,
+            args: Vec::new(),
+            payment,
+            forward_cycles,
+            request_id: None,
+            max_fee: None,
+        },
+        request_bytes.unwrap_or(0),
+    )
+}
+
+// --------------------------------------------------------------------------
+// Payment preferences
+//
+// Ingress messages cannot attach cycles, so a user calling the wrapper
+// directly has to name a payment type on every call unless it sets a
+// preference.
+// --------------------------------------------------------------------------
+
+/// Set the payment type used for the caller's calls that do not name one, or
+/// with `None` go back to attached cycles. Returns the prior preference.
+///
+/// A preference that goes unused for 90 days expires.
+#[update]
+pub fn set_payment_preference(payment: Option<PaymentType>) -> Result<Option<PaymentType>, String> {
+    let caller = msg_caller();
+    let Some(payment) = payment else {
+        return Ok(state::remove_payment_preference(&caller));
+    };
+    if caller == Principal::anonymous() {
+        return Err("The anonymous principal cannot set a payment preference.".to_string());
+    }
+    if PAYMENT_GUARD.config(payment.clone()).is_none() {
+        return Err(format!("The wrapper does not accept {payment:?}."));
+    }
+    state::set_payment_preference(caller, payment, ic_cdk::api::time()).map_err(|max| {
+        format!("The wrapper already keeps the payment preferences of {max} callers.")
+    })
+}
+
+/// The caller's payment preference, if it set one.
+#[query]
+#[must_use]
+pub fn get_payment_preference() -> Option<PaymentType> {
+    state::get_payment_preference(&msg_caller(), ic_cdk::api::time())
+}
+
+// --------------------------------------------------------------------------
+// Roles
+//
+// Each configuration endpoint requires a `Role`. Controllers implicitly hold
+// every role, so that a freshly installed wrapper can be configured, and admins
+// may grant roles to others, e.g. to let on-call staff pause targets without
+// being able to upgrade the canister.
+// --------------------------------------------------------------------------
+
+fn ensure_role(role: Role) -> Result<(), String> {
+    let caller = msg_caller();
+    if is_controller(&caller) || state::has_role(&caller, role) {
+        Ok(())
+    } else {
+        Err(format!(
+            "Only a canister controller or a principal with the {role:?} role may do this."
+        ))
+    }
+}
+
+/// Pricing managers may price any method, and the registered owner of a
+/// target may price that target's methods, except that an owner may not make
+/// the wrapper wait on its target without a bound, which would block upgrades.
+fn ensure_may_price(target: Principal, config: Option<&MethodConfig>) -> Result<(), String> {
+    if ensure_role(Role::PricingManager).is_ok() {
+        return Ok(());
+    }
+    if state::get_target_owner(&target) != Some(msg_caller()) {
+        return Err(format!(
+            "Only a canister controller, a principal with the PricingManager role, or the \
+             registered owner of `{target}` may do this."
+        ));
+    }
+    if config.is_some_and(|config| matches!(config.wait, Some(CallWait::Unbounded))) {
+        return Err("Only a pricing manager may configure an unbounded wait.".to_string());
+    }
+    Ok(())
+}
+
+/// Grant `role` to `principal`. Returns `false` if it already held it.
+#[update]
+pub fn grant_role(principal: Principal, role: Role) -> Result<bool, String> {
+    ensure_role(Role::Admin)?;
+    Ok(state::grant_role(principal, role))
+}
+
+/// Revoke `role` from `principal`. Returns `false` if it did not hold it.
+///
+/// Controllers keep every role regardless.
+#[update]
+pub fn revoke_role(principal: Principal, role: Role) -> Result<bool, String> {
+    ensure_role(Role::Admin)?;
+    Ok(state::revoke_role(&principal, role))
+}
+
+/// List every principal's granted roles. Controllers are not listed.
+#[query]
+pub fn list_roles() -> Result<Vec<(Principal, Vec<Role>)>, String> {
+    ensure_role(Role::Auditor)?;
+    Ok(state::list_roles())
+}
+
+// --------------------------------------------------------------------------
+// Operator configuration (pricing managers)
+//
+// Pricing is server-side: the operator registers, per `(target, method)`, the
+// fee to charge and the cycles to forward. Callers can never set these, so they
+// cannot make the wrapper forward more cycles than it is paid.
+// --------------------------------------------------------------------------
+
+/// Reject configurations that would let the wrapper forward more cycles than the
+/// fee funds, at the wrapper's own token-to-cycles rates (see
+/// [`validate::validate_config`]).
+pub(crate) fn validate_config(config: &MethodConfig) -> Result<(), String> {
+    validate::validate_config(config, state::get_rate)
+}
+
+/// Whether `config` may price `key` on `target` without mixing calls that are
+/// wrapped in a caller envelope with calls that are not: a target that
+/// accepts envelopes from the wrapper must never receive the caller's own
+/// arguments, which could be a forged envelope.
+pub(crate) fn check_envelope_setting(
+    key: &ConfigKey,
+    target: &Principal,
+    config: &MethodConfig,
+) -> Result<(), String> {
+    if state::envelope_settings(target, key)
+        .into_iter()
+        .any(|other| other != config.forwards_caller())
+    {
+        return Err(format!(
+            "Every price on canister `{target}` must set forward_caller alike, so that either \
+             every call to it is wrapped in a caller envelope or none is."
+        ));
+    }
+    Ok(())
+}
+
+/// Rules may not name the wrapper itself or the management canister, whose
+/// methods must be priced one by one.
+pub(crate) fn check_rule_target(rule: &PricingRule) -> Result<(), String> {
+    if rule.target == ic_cdk::api::canister_self()
+        || rule.target == Principal::management_canister()
+    {
+        return Err(format!(
+            "Pricing rules may not name canister `{}`; price its methods one by one.",
+            rule.target
+        ));
+    }
+    Ok(())
+}
+
+/// Fails if changing the price callers pay from `old` to `new` raises it
+/// sooner than the minimum notice period after `now`.
+fn check_increase_notice(
+    old: Option<&MethodConfig>,
+    new: Option<&MethodConfig>,
+    now: u64,
+    effective_at: u64,
+) -> Result<(), String> {
+    let notice = state::get_min_increase_notice();
+    if MethodConfig::change_raises_price(old, new) && effective_at < now.saturating_add(notice) {
+        return Err(format!(
+            "This raises the price, so it must take effect at least {notice} nanoseconds \
+             from now, at or after {}.",
+            now.saturating_add(notice)
+        ));
+    }
+    Ok(())
+}
+
+/// Register or replace the price for a `(target, method)` pair.
+///
+/// The price takes effect at `effective_at` (nanoseconds since the epoch), or
+/// immediately if it is unset or in the past; it replaces any change already
+/// scheduled for the pair. A price increase must be scheduled at least the
+/// minimum notice period ahead, so that callers can adjust their allowances.
+/// It is measured against the price callers pay now, whether it was set for
+/// the pair itself or by a pricing rule.
+#[update]
+pub fn set_method_config(
+    key: MethodKey,
+    config: MethodConfig,
+    effective_at: Option<u64>,
+) -> Result<(), String> {
+    ensure_may_price(key.target, Some(&config))?;
+    check_method_target(&key, &config)?;
+    validate_config(&config)?;
+    check_envelope_setting(&ConfigKey::Method(key.clone()), &key.target, &config)?;
+    let now = ic_cdk::api::time();
+    state::apply_due_configs(now);
+    let effective_at = effective_at.unwrap_or(now).max(now);
+    check_increase_notice(
+        state::resolve_config(&key).as_ref(),
+        Some(&config),
+        now,
+        effective_at,
+    )?;
+    let current = state::get_config(&key);
+    let _ = state::cancel_pending_config(&key);
+    if effective_at > now {
+        state::schedule_config(
+            key.clone(),
+            PendingConfig {
+                config: Some(config.clone()),
+                effective_at,
+            },
+        );
+    } else {
+        state::clear_cached_replies(&key);
+        state::set_config(key.clone(), config.clone());
+    }
+    state::record_config_change(
+        msg_caller(),
+        now,
+        ConfigKey::Method(key),
+        current,
+        Some(config),
+        (effective_at > now).then_some(effective_at),
+    );
+    Ok(())
+}
+
+/// Remove the price for a `(target, method)` pair, returning any prior value.
+///
+/// The removal takes effect at `effective_at`, as with `set_method_config`,
+/// and replaces any change already scheduled for the pair. Removing a price
+/// raises it, unless a pricing rule charges no more, so it needs the same
+/// notice; pause the method to stop calls at once.
+#[update]
+#[allow(clippy::needless_pass_by_value)]
+pub fn remove_method_config(
+    key: MethodKey,
+    effective_at: Option<u64>,
+) -> Result<Option<MethodConfig>, String> {
+    ensure_may_price(key.target, None)?;
+    let now = ic_cdk::api::time();
+    state::apply_due_configs(now);
+    let effective_at = effective_at.unwrap_or(now).max(now);
+    check_increase_notice(
+        state::resolve_config(&key).as_ref(),
+        state::resolve_rule(&key).as_ref(),
+        now,
+        effective_at,
+    )?;
+    let _ = state::cancel_pending_config(&key);
+    let old = if effective_at > now {
+        state::schedule_config(
+            key.clone(),
+            PendingConfig {
+                config: None,
+                effective_at,
+            },
+        );
+        state::get_config(&key)
+    } else {
+        state::clear_cached_replies(&key);
+        state::remove_config(&key)
+    };
+    state::record_config_change(
+        msg_caller(),
+        now,
+        ConfigKey::Method(key),
+        old.clone(),
+        None,
+        (effective_at > now).then_some(effective_at),
+    );
+    Ok(old)
+}
+
+/// Cancel the price change scheduled for a `(target, method)` pair, returning it.
+#[update]
+#[allow(clippy::needless_pass_by_value)]
+pub fn cancel_pending_method_config(key: MethodKey) -> Result<Option<PendingConfig>, String> {
+    ensure_may_price(key.target, None)?;
+    let now = ic_cdk::api::time();
+    state::apply_due_configs(now);
+    let pending = state::cancel_pending_config(&key);
+    if let Some(pending) = &pending {
+        // Record the cancelled price being replaced, when it would have
+        // applied, by the price that stays.
+        state::record_config_change(
+            msg_caller(),
+            now,
+            ConfigKey::Method(key.clone()),
+            pending.config.clone(),
+            state::get_config(&key),
+            Some(pending.effective_at),
+        );
+    }
+    Ok(pending)
+}
+
+/// Read the current price of a `(target, method)` pair, and the change
+/// scheduled for it, if any.
+#[query]
+#[must_use]
+#[allow(clippy::needless_pass_by_value)]
+pub fn get_method_config(key: MethodKey) -> MethodPrices {
+    let current = state::get_config(&key);
+    match state::get_pending_config(&key) {
+        Some(pending) if pending.effective_at <= ic_cdk::api::time() => MethodPrices {
+            current: pending.config,
+            pending: None,
+        },
+        pending => MethodPrices { current, pending },
+    }
+}
+
+/// List every scheduled price change.
+#[query]
+#[must_use]
+pub fn list_pending_method_configs() -> Vec<(MethodKey, PendingConfig)> {
+    state::list_pending_configs()
+}
+
+/// Set how many seconds ahead a price increase must be scheduled.
+#[update]
+pub fn set_min_price_increase_notice(seconds: u64) -> Result<(), String> {
+    ensure_role(Role::Admin)?;
+    state::set_min_increase_notice(seconds.saturating_mul(1_000_000_000));
+    Ok(())
+}
+
+/// How many seconds ahead a price increase must be scheduled.
+#[query]
+#[must_use]
+pub fn get_min_price_increase_notice() -> u64 {
+    state::get_min_increase_notice() / 1_000_000_000
+}
+
+/// List the management-canister methods that may be priced with
+/// `set_method_config`. All other management methods are blocked.
+#[query]
+#[must_use]
+pub fn list_priceable_management_methods() -> Vec<String> {
+    PRICEABLE_MANAGEMENT_METHODS
+        .iter()
+        .map(ToString::to_string)
+        .collect()
+}
+
+/// List every configured `(target, method)` price.
+#[query]
+#[must_use]
+pub fn list_method_configs() -> Vec<(MethodKey, MethodConfig)> {
+    state::list_configs()
+}
+
+/// Register or replace a price for every method of a target whose name starts
+/// with `rule.method_prefix` (all of its methods, if the prefix is empty).
+///
+/// Exact prices set with `set_method_config` take precedence. Rules may not
+/// name the wrapper itself or the management canister, whose methods must be
+/// priced one by one. The rule takes effect at `effective_at`, and raising
+/// the price of the methods it covers needs notice, as with
+/// `set_method_config`.
+#[update]
+pub fn set_pricing_rule(
+    rule: PricingRule,
+    config: MethodConfig,
+    effective_at: Option<u64>,
+) -> Result<(), String> {
+    ensure_role(Role::PricingManager)?;
+    check_rule_target(&rule)?;
+    validate_config(&config)?;
+    check_envelope_setting(&ConfigKey::Rule(rule.clone()), &rule.target, &config)?;
+    let now = ic_cdk::api::time();
+    state::apply_due_configs(now);
+    let effective_at = effective_at.unwrap_or(now).max(now);
+    check_increase_notice(
+        state::rule_price(&rule).as_ref(),
+        Some(&config),
+        now,
+        effective_at,
+    )?;
+    let _ = state::cancel_pending_rule(&rule);
+    let old = if effective_at > now {
+        state::schedule_rule(
+            rule.clone(),
+            PendingConfig {
+                config: Some(config.clone()),
+                effective_at,
+            },
+        );
+        state::get_rule(&rule)
+    } else {
+        state::clear_cached_replies_for_rule(&rule);
+        state::set_rule(rule.clone(), config.clone())
+    };
+    state::record_config_change(
+        msg_caller(),
+        now,
+        ConfigKey::Rule(rule),
+        old,
+        Some(config),
+        (effective_at > now).then_some(effective_at),
+    );
+    Ok(())
+}
+
+/// Remove a pricing rule, returning any prior value.
+///
+/// The removal takes effect at `effective_at` and needs notice if it raises
+/// the price of the methods the rule covers, as with `remove_method_config`.
+#[update]
+#[allow(clippy::needless_pass_by_value)]
+pub fn remove_pricing_rule(
+    rule: PricingRule,
+    effective_at: Option<u64>,
+) -> Result<Option<MethodConfig>, String> {
+    ensure_role(Role::PricingManager)?;
+    let now = ic_cdk::api::time();
+    state::apply_due_configs(now);
+    let effective_at = effective_at.unwrap_or(now).max(now);
+    check_increase_notice(
+        state::rule_price(&rule).as_ref(),
+        state::price_without_rule(&rule).as_ref(),
+        now,
+        effective_at,
+    )?;
+    let _ = state::cancel_pending_rule(&rule);
+    let old = if effective_at > now {
+        state::schedule_rule(
+            rule.clone(),
+            PendingConfig {
+                config: None,
+                effective_at,
+            },
+        );
+        state::get_rule(&rule)
+    } else {
+        state::clear_cached_replies_for_rule(&rule);
+        state::remove_rule(&rule)
+    };
+    state::record_config_change(
+        msg_caller(),
+        now,
+        ConfigKey::Rule(rule),
+        old.clone(),
+        None,
+        (effective_at > now).then_some(effective_at),
+    );
+    Ok(old)
+}
+
+/// Cancel the change scheduled for a pricing rule, returning it.
+#[update]
+#[allow(clippy::needless_pass_by_value)]
+pub fn cancel_pending_pricing_rule(rule: PricingRule) -> Result<Option<PendingConfig>, String> {
+    ensure_role(Role::PricingManager)?;
+    let now = ic_cdk::api::time();
+    state::apply_due_configs(now);
+    let pending = state::cancel_pending_rule(&rule);
+    if let Some(pending) = &pending {
+        state::record_config_change(
+            msg_caller(),
+            now,
+            ConfigKey::Rule(rule.clone()),
+            pending.config.clone(),
+            state::get_rule(&rule),
+            Some(pending.effective_at),
+        );
+    }
+    Ok(pending)
+}
+
+/// List every pricing rule.
+#[query]
+#[must_use]
+pub fn list_pricing_rules() -> Vec<(PricingRule, MethodConfig)> {
+    state::list_rules()
+}
+
+/// List every scheduled pricing rule change.
+#[query]
+#[must_use]
+pub fn list_pending_pricing_rules() -> Vec<(PricingRule, PendingConfig)> {
+    state::list_pending_rules()
+}
+
+/// The price that applies to a `(target, method)` pair, whether it was set
+/// for the pair itself or by a pricing rule.
+#[query]
+#[must_use]
+#[allow(clippy::needless_pass_by_value)]
+pub fn resolve_method_config(key: MethodKey) -> Option<MethodConfig> {
+    state::resolve_config(&key)
+}
+
+/// Read the audit log of price and pricing rule changes, oldest first.
+///
+/// Returns at most `limit` entries (and never more than 100), starting at
+/// entry `from`, or at the oldest entry still kept; follow `next` for the
+/// following page.
+#[query]
+pub fn list_config_changes(
+    from: Option<u64>,
+    limit: Option<u64>,
+) -> Result<ConfigChangePage, String> {
+    ensure_role(Role::Auditor)?;
+    let limit = limit
+        .and_then(|limit| usize::try_from(limit).ok())
+        .unwrap_or(state::MAX_CONFIG_CHANGES_PAGE)
+        .min(state::MAX_CONFIG_CHANGES_PAGE);
+    Ok(state::config_changes(from.unwrap_or(0), limit))
+}
+
+/// Export every price and pricing rule, e.g. to set up another wrapper.
+#[query]
+pub fn export_config() -> Result<ConfigExport, String> {
+    ensure_role(Role::Auditor)?;
+    Ok(config::export())
+}
+
+/// Import prices and pricing rules exported by `export_config`.
+///
+/// Every entry is validated as by `set_method_config` and `set_pricing_rule`
+/// before anything changes, so either the whole import applies or none of it
+/// does. With `dry_run`, nothing changes either way.
+#[update]
+pub fn import_config(args: ImportConfigArgs) -> Result<ImportSummary, String> {
+    ensure_role(Role::PricingManager)?;
+    config::import(msg_caller(), ic_cdk::api::time(), args)
+}
+
+// --------------------------------------------------------------------------
+// Cycles reserve and ledger fees (pricing managers set rates and fees; admins
+// move cycles)
+//
+// Calls paid in tokens credit a token account, not the wrapper's cycle
+// balance. To let such calls forward cycles, the operator sets aside a reserve
+// of cycles and a per-ledger rate at which token fees buy from it.
+// --------------------------------------------------------------------------
+
+/// Set the rate at which fees paid in tokens on `ledger` fund forwarded cycles.
+#[update]
+pub fn set_token_cycles_rate(ledger: Principal, rate: TokenCyclesRate) -> Result<(), String> {
+    ensure_role(Role::PricingManager)?;
+    if rate.tokens == 0 || rate.cycles == 0 {
+        return Err("Both sides of a token-to-cycles rate must be non-zero.".to_string());
+    }
+    state::set_rate(ledger, rate);
+    Ok(())
+}
+
+/// Remove the token-to-cycles rate for `ledger`, returning any prior value.
+///
+/// Calls paid on that ledger can then no longer forward cycles.
+#[update]
+pub fn remove_token_cycles_rate(ledger: Principal) -> Result<Option<TokenCyclesRate>, String> {
+    ensure_role(Role::PricingManager)?;
+    Ok(state::remove_rate(&ledger))
+}
+
+/// List every configured token-to-cycles rate.
+#[query]
+#[must_use]
+pub fn list_token_cycles_rates() -> Vec<(Principal, TokenCyclesRate)> {
+    state::list_rates()
+}
+
+/// Record the fee `ledger` charges for a transfer, so that `estimate_cost`
+/// can include it in the allowance to approve. The cycles ledger's fee is
+/// known without this.
+#[update]
+pub fn set_ledger_fee(ledger: Principal, fee: u128) -> Result<(), String> {
+    ensure_role(Role::PricingManager)?;
+    state::set_ledger_fee(ledger, fee);
+    Ok(())
+}
+
+/// Forget the recorded fee for `ledger`, returning any prior value.
+#[update]
+pub fn remove_ledger_fee(ledger: Principal) -> Result<Option<u128>, String> {
+    ensure_role(Role::PricingManager)?;
+    Ok(state::remove_ledger_fee(&ledger))
+}
+
+/// List every recorded ledger fee.
+#[query]
+#[must_use]
+pub fn list_ledger_fees() -> Vec<(Principal, u128)> {
+    state::list_ledger_fees()
+}
+
+/// Set aside `amount` more of the wrapper's cycles to fund forwarding for calls paid in tokens.
+#[update]
+pub fn allocate_cycles_reserve(amount: u128) -> Result<CyclesReserve, String> {
+    ensure_role(Role::Admin)?;
+    let mut reserve = state::get_reserve();
+    let balance = reserve
+        .balance
+        .checked_add(amount)
+        .ok_or_else(|| "The reserve would overflow.".to_string())?;
+    let held = canister_cycle_balance();
+    if held < balance {
+        return Err(format!(
+            "The wrapper holds {held} cycles, which cannot back a reserve of {balance} cycles."
+        ));
+    }
+    reserve.balance = balance;
+    state::set_reserve(reserve);
+    Ok(reserve)
+}
+
+/// Return `amount` cycles from the reserve to the wrapper's general balance.
+#[update]
+pub fn release_cycles_reserve(amount: u128) -> Result<CyclesReserve, String> {
+    ensure_role(Role::Admin)?;
+    let mut reserve = state::get_reserve();
+    reserve.balance = reserve.balance.checked_sub(amount).ok_or_else(|| {
+        format!(
+            "Cannot release {amount} cycles: the reserve holds {}.",
+            reserve.balance
+        )
+    })?;
+    state::set_reserve(reserve);
+    Ok(reserve)
+}
+
+/// The balance of, and cycles spent from, the reserve.
+#[query]
+#[must_use]
+pub fn get_cycles_reserve() -> CyclesReserve {
+    state::get_reserve()
+}
+
+// --------------------------------------------------------------------------
+// Target owners
+//
+// The controllers of a target canister may register it and, once an admin
+// approves, price its methods themselves with `set_method_config`. The
+// revenue of every call to a registered target, less the platform fee,
+// accrues to its owner, who may withdraw it.
+// --------------------------------------------------------------------------
+
+/// Ask for the caller to become the owner of `target`, which it must control.
+/// The registration waits for an admin's approval. Returns any claim it
+/// replaces.
+#[update]
+pub async fn register_target(target: Principal) -> Result<Option<Principal>, String> {
+    owners::register(msg_caller(), target).await
+}
+
+/// Approve the pending registration of `target`, returning its prior owner.
+/// Admin only.
+#[update]
+pub fn approve_target_registration(target: Principal) -> Result<Option<Principal>, String> {
+    ensure_role(Role::Admin)?;
+    let claimant = state::take_target_claim(&target)
+        .ok_or_else(|| format!("No registration of `{target}` is pending."))?;
+    Ok(state::set_target_owner(target, claimant))
+}
+
+/// Reject the pending registration of `target`, returning who asked. Allowed
+/// for the claimant and admins.
+#[update]
+pub fn reject_target_registration(target: Principal) -> Result<Option<Principal>, String> {
+    if state::list_target_claims().contains(&(target, msg_caller())) {
+        return Ok(state::take_target_claim(&target));
+    }
+    ensure_role(Role::Admin)?;
+    Ok(state::take_target_claim(&target))
+}
+
+/// List every registration awaiting approval, as `(target, claimant)` pairs.
+#[query]
+#[must_use]
+pub fn list_pending_target_registrations() -> Vec<(Principal, Principal)> {
+    state::list_target_claims()
+}
+
+/// Unregister `target`, returning its owner. Allowed for the owner and admins.
+///
+/// The owner keeps its balances and the target keeps its prices, but calls no
+/// longer earn the owner anything.
+#[update]
+pub fn unregister_target(target: Principal) -> Result<Option<Principal>, String> {
+    if state::get_target_owner(&target) != Some(msg_caller()) {
+        ensure_role(Role::Admin)?;
+    }
+    Ok(state::remove_target_owner(&target))
+}
+
+/// The registered owner of `target`, if any.
+#[query]
+#[must_use]
+pub fn get_target_owner(target: Principal) -> Option<Principal> {
+    state::get_target_owner(&target)
+}
+
+/// List every registered target and its owner.
+#[query]
+#[must_use]
+pub fn list_target_owners() -> Vec<(Principal, Principal)> {
+    state::list_target_owners()
+}
+
+/// The caller's balances as a target owner, by denomination.
+#[query]
+#[must_use]
+pub fn get_owner_balances() -> Vec<(FeeDenom, u128)> {
+    state::owner_balances(&msg_caller())
+}
+
+/// Withdraw `amount` of the caller's balance in `denom` to `to`, by default
+/// the caller's main account. Returns the amount received: for tokens, the
+/// ledger's transfer fee is taken out of `amount`.
+#[update]
+pub async fn withdraw_owner_balance(
+    denom: FeeDenom,
+    amount: u128,
+    to: Option<Account>,
+) -> Result<u128, String> {
+    let owner = msg_caller();
+    let to = to.unwrap_or(Account {
+        owner,
+        subaccount: None,
+    });
+    owners::withdraw(owner, denom, amount, to).await
+}
+
+/// Set the share of owners' revenue the wrapper keeps, in basis points.
+#[update]
+pub fn set_platform_fee(bps: u32) -> Result<(), String> {
+    ensure_role(Role::Admin)?;
+    if bps > 10_000 {
+        return Err("The platform fee may not exceed 10_000 basis points.".to_string());
+    }
+    state::set_platform_fee_bps(bps);
+    Ok(())
+}
+
+/// The share of owners' revenue the wrapper keeps, in basis points.
+#[query]
+#[must_use]
+pub fn get_platform_fee() -> u32 {
+    state::get_platform_fee_bps()
+}
+
+// --------------------------------------------------------------------------
+// Pauses, circuit breaker and module pins (operators)
+//
+// Operators can stop traffic to a misbehaving target, or to one of its
+// methods, without deleting its prices. The circuit breaker does the same
+// automatically, for a while, after repeated failures, and a module pin does
+// it when the target's code changes.
+// --------------------------------------------------------------------------
+
+/// Refuse all calls to `target` until it is resumed. Returns `false` if it was
+/// already paused.
+#[update]
+pub fn pause_target(target: Principal) -> Result<bool, String> {
+    ensure_role(Role::Operator)?;
+    Ok(state::pause_target(target))
+}
+
+/// Allow calls to `target` again. Returns `false` if it was not paused.
+#[update]
+pub fn resume_target(target: Principal) -> Result<bool, String> {
+    ensure_role(Role::Operator)?;
+    Ok(state::resume_target(&target))
+}
+
+/// Refuse calls to one `(target, method)` pair until it is resumed. Returns
+/// `false` if it was already paused.
+#[update]
+pub fn pause_method(key: MethodKey) -> Result<bool, String> {
+    ensure_role(Role::Operator)?;
+    Ok(state::pause_method(key))
+}
+
+/// Allow calls to a `(target, method)` pair again. Returns `false` if it was
+/// not paused.
+#[update]
+#[allow(clippy::needless_pass_by_value)]
+pub fn resume_method(key: MethodKey) -> Result<bool, String> {
+    ensure_role(Role::Operator)?;
+    Ok(state::resume_method(&key))
+}
+
+/// List the paused targets and methods.
+#[query]
+#[must_use]
+pub fn list_pauses() -> Pauses {
+    state::get_pauses()
+}
+
+/// Enable or change the circuit breaker, or disable it with `None`. Resets
+/// the breakers of all targets.
+#[update]
+pub fn set_circuit_breaker(config: Option<CircuitBreakerConfig>) -> Result<(), String> {
+    ensure_role(Role::Operator)?;
+    if let Some(config) = &config {
+        if config.failure_threshold == 0 {
+            return Err("failure_threshold must be greater than zero.".to_string());
+        }
+        if config.cooldown_seconds == 0 {
+            return Err("cooldown_seconds must be greater than zero.".to_string());
+        }
+    }
+    state::set_breaker_config(config);
+    Ok(())
+}
+
+/// The circuit breaker configuration, if it is enabled.
+#[query]
+#[must_use]
+pub fn get_circuit_breaker() -> Option<CircuitBreakerConfig> {
+    state::get_breaker_config()
+}
+
+/// List the targets that have recently failed, and whether their breaker is open.
+#[query]
+#[must_use]
+pub fn list_circuit_breakers() -> Vec<CircuitBreakerStatus> {
+    state::list_breakers()
+}
+
+/// Close the circuit breaker of `target`, allowing calls again.
+#[update]
+pub fn reset_circuit_breaker(target: Principal) -> Result<(), String> {
+    ensure_role(Role::Operator)?;
+    state::reset_breaker(&target);
+    Ok(())
+}
+
+/// Approve `hash` (the SHA-256 of a Wasm module) as the code of `target`,
+/// returning the prior pin. Calls to `target` are then refused, uncharged,
+/// once it is found running any other code, until a hash is pinned again.
+#[update]
+pub fn pin_module_hash(target: Principal, hash: ByteBuf) -> Result<Option<ModulePin>, String> {
+    ensure_role(Role::Operator)?;
+    if target == ic_cdk::api::canister_self() || target == Principal::management_canister() {
+        return Err(format!("The code of canister `{target}` cannot be pinned."));
+    }
+    if hash.len() != 32 {
+        return Err("A module hash must be 32 bytes long.".to_string());
+    }
+    Ok(state::pin_module(target, hash.into_vec()))
+}
+
+/// Stop checking the code of `target`, returning its pin.
+#[update]
+pub fn unpin_module_hash(target: Principal) -> Result<Option<ModulePin>, String> {
+    ensure_role(Role::Operator)?;
+    Ok(state::unpin_module(&target))
+}
+
+/// List the pinned targets, and any change of code found on them.
+#[query]
+#[must_use]
+pub fn list_module_pins() -> Vec<(Principal, ModulePin)> {
+    state::list_module_pins()
+}
+
+// --------------------------------------------------------------------------
+// Upgrade persistence
+// --------------------------------------------------------------------------
+
+/// The state that the first release of the wrapper saved in `pre_upgrade`,
+/// when it kept its price registry on the heap.
+type SavedState = (Vec<(MethodKey, MethodConfig)>,);
+
+/// Migrates state saved by the first release into stable structures. State
+/// kept in stable structures needs no work.
+#[post_upgrade]
+fn post_upgrade() {
+    if !state::holds_legacy_state() {
+        return;
+    }
+    // Trapping rolls the upgrade back, leaving the old version and its state
+    // in place, which beats coming up with an empty registry.
+    let saved = ic_cdk::storage::stable_restore::<SavedState>().unwrap_or_else(|err| {
+        ic_cdk::trap(format!(
+            "post_upgrade: failed to decode the state saved by the previous version: {err}"
+        ))
+    });
+    restore_legacy_state(saved);
+}
+
+/// Writes the price registry saved by the first release into the stable
+/// structures. Everything else starts from its defaults.
+fn restore_legacy_state((configs,): SavedState) {
+    state::replace_all(configs);
+}
+
+export_candid!();
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::types::FeeSpec;
+
+    #[test]
+    fn legacy_state_is_migrated() {
+        /// A price as the first release stored it, before any optional
+        /// behaviour was added.
+        #[derive(candid::CandidType)]
+        struct FirstReleaseConfig {
+            fee: FeeSpec,
+            supported: Vec<ic_papi_guard::guards::any::VendorPaymentConfig>,
+            forward_cycles: Option<u128>,
+        }
+        let key = MethodKey {
+            target: Principal::from_slice(&[2]),
+            method: "get".to_string(),
+        };
+        let price = MethodConfig {
+            forward_cycles: Some(500),
+            ..MethodConfig::cycles(1_000)
+        };
+        let bytes = candid::encode_args((vec![(
+            key.clone(),
+            FirstReleaseConfig {
+                fee: price.fee.clone(),
+                supported: vec![],
+                forward_cycles: price.forward_cycles,
+            },
+        )],))
+        .unwrap();
+        let mut de = candid::de::IDLDeserialize::new(&bytes).unwrap();
+        let saved: SavedState = candid::utils::ArgumentDecoder::decode(&mut de).unwrap();
+        restore_legacy_state(saved);
+
+        assert_eq!(state::list_configs(), vec![(key, price)]);
+        assert_eq!(
+            state::get_platform_fee_bps(),
+            state::DEFAULT_PLATFORM_FEE_BPS
+        );
+        assert_eq!(
+            state::get_min_increase_notice(),
+            state::DEFAULT_MIN_INCREASE_NOTICE_NANOS
+        );
+    }
+}
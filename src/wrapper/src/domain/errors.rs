@@ -1,22 +1,170 @@
 /// Errors returned by the bridge canister.
+use candid::{CandidType, Deserialize, Principal};
+use ic_papi_api::PaymentError;
+use serde_bytes::ByteBuf;
 use std::fmt;
 
-#[derive(Debug)]
+use crate::domain::types::{FeeDenom, FeeSpec};
+
+#[derive(Debug, CandidType, Deserialize, Clone, Eq, PartialEq)]
 pub enum BridgeError {
     /// Candid encoding/decoding failed.
     Candid(String),
     /// Target canister rejected the proxied call.
-    TargetRejected(String),
+    TargetRejected(TargetReject),
+    /// Target canister rejected the proxied call, and the method's refund policy
+    /// returned `refunded` to the payer.
+    TargetRejectedRefunded {
+        reason: TargetReject,
+        refunded: u128,
+    },
+    /// Target canister rejected the proxied call, and returning the refund to the
+    /// payer failed.
+    RefundFailed {
+        reason: TargetReject,
+        refund: u128,
+        error: String,
+    },
+    /// A bounded-wait call to the target ended without a reply, e.g. because it
+    /// timed out. The target may or may not have executed the call, so the fee
+    /// is not refunded.
+    OutcomeUnknown(TargetReject),
     /// Fee deduction failed (insufficient cycles/allowance/etc.).
-    GuardError(String),
+    GuardError(Box<PaymentError>),
     /// No operator-configured price exists for the requested `(target, method)`.
-    MethodNotConfigured { target: String, method: String },
+    MethodNotConfigured { target: Principal, method: String },
+    /// The configuration priced for `(target, method)` is not valid, e.g. it
+    /// forwards more cycles than its fee funds, so the call is refused.
+    MethodMisconfigured {
+        target: Principal,
+        method: String,
+        reason: String,
+    },
     /// The configured method forwards cycles, but the chosen payment type is not
-    /// cycle-denominated, so the forwarded cycles would come out of the wrapper's
-    /// own balance rather than being funded by the payment.
+    /// cycle-denominated and no token-to-cycles rate is configured for its ledger,
+    /// so the forwarded cycles would come out of the wrapper's own balance rather
+    /// than being funded by the payment.
     ForwardRequiresCyclePayment,
+    /// A token fee, converted at the operator's rate, is worth fewer cycles than
+    /// the method forwards.
+    FeeDoesNotCoverForwardedCycles {
+        fee_in_cycles: u128,
+        forward_cycles: u128,
+    },
+    /// The cycles reserve that funds forwarding for token payments is too low.
+    InsufficientCyclesReserve { needed: u128, available: u128 },
+    /// The wrapper may not call itself.
+    SelfCall,
     /// The requested target canister may not be reached through the bridge.
     ForbiddenTarget(String),
+    /// The operator has paused all calls to the target.
+    TargetPaused { target: Principal },
+    /// The operator has paused calls to this method.
+    MethodPaused { target: Principal, method: String },
+    /// The target's circuit breaker is open after repeated failures; calls are
+    /// refused until `open_until`, in nanoseconds since the epoch.
+    CircuitOpen { target: Principal, open_until: u64 },
+    /// The target no longer runs the module the operator pinned, so calls are
+    /// refused until the operator approves its code again. `actual` is `None`
+    /// if the target has no code installed.
+    ModuleHashChanged {
+        target: Principal,
+        expected: ByteBuf,
+        actual: Option<ByteBuf>,
+    },
+    /// The target's module hash could not be checked against its pin.
+    ModuleHashUnavailable { target: Principal, reason: String },
+    /// The caller chose how many cycles to forward, but the method forwards a fixed amount.
+    ForwardCyclesNotSelectable,
+    /// The caller chose to forward a number of cycles outside the method's range.
+    ForwardCyclesOutOfRange {
+        requested: u128,
+        min: u128,
+        max: u128,
+    },
+    /// The price of the call is higher than the caller's `max_fee`, or in a
+    /// different denomination.
+    FeeExceedsMax { fee: FeeSpec, max_fee: FeeSpec },
+    /// The price of the call does not fit in a `u128`.
+    PriceOverflow,
+    /// The caller-supplied request id is too long.
+    InvalidRequestId { max_bytes: u64 },
+    /// A call with the same request id is still being processed.
+    RequestInProgress,
+    /// The request id was already used by this caller for a different call.
+    RequestIdReused { target: Principal, method: String },
+    /// The request id was already used for a call to the same method with
+    /// other arguments, payment type, forwarded cycles or fee cap.
+    RequestIdReusedWithOtherArgs,
+    /// No more requests can be remembered for the caller, or for anyone,
+    /// because `max_requests` calls with request ids are still in flight.
+    TooManyRequests { max_requests: u64 },
+    /// A batch must contain at least one call.
+    EmptyBatch,
+    /// A batch may contain at most `max_calls` calls.
+    BatchTooLarge { max_calls: u64 },
+    /// A call in a batch is priced in `fee`, but the batch is paid in
+    /// `payment`. All calls of a batch must be priced in the denomination of
+    /// its payment, so that their fees can be charged together.
+    FeeDenomMismatch {
+        fee: FeeDenom,
+        payment: Option<FeeDenom>,
+    },
+    /// `call_text` is disabled in this build.
+    CallTextDisabled,
+}
+
+/// Why a forwarded call to the target failed.
+#[derive(Debug, CandidType, Deserialize, Clone, Eq, PartialEq)]
+pub struct TargetReject {
+    /// The reject code, if the call was made and rejected.
+    pub reject_code: Option<RejectCode>,
+    /// The reject message, or why the call could not be made.
+    pub message: String,
+    /// Whether the target may have executed the call, and changed its state,
+    /// despite the reject. If `false`, the call certainly had no effect.
+    pub may_have_executed: bool,
+}
+
+/// The reject codes of the Internet Computer.
+#[derive(Debug, CandidType, Deserialize, Clone, Copy, Eq, PartialEq)]
+pub enum RejectCode {
+    SysFatal,
+    SysTransient,
+    DestinationInvalid,
+    CanisterReject,
+    CanisterError,
+    /// The outcome is unknown, e.g. because a bounded-wait call timed out.
+    SysUnknown,
+    /// A reject code this version of the wrapper does not know.
+    Unrecognized(u32),
+}
+
+impl From<u32> for RejectCode {
+    fn from(code: u32) -> Self {
+        match code {
+            1 => RejectCode::SysFatal,
+            2 => RejectCode::SysTransient,
+            3 => RejectCode::DestinationInvalid,
+            4 => RejectCode::CanisterReject,
+            5 => RejectCode::CanisterError,
+            6 => RejectCode::SysUnknown,
+            code => RejectCode::Unrecognized(code),
+        }
+    }
+}
+
+impl fmt::Display for TargetReject {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.reject_code {
+            Some(code) => write!(f, "{code:?} - {}", self.message)?,
+            None => write!(f, "{}", self.message)?,
+        }
+        if self.may_have_executed {
+            write!(f, " (the target may have executed the call)")?;
+        }
+        Ok(())
+    }
 }
 
 impl fmt::Display for BridgeError {
@@ -24,19 +172,140 @@ impl fmt::Display for BridgeError {
         match self {
             BridgeError::Candid(e) => write!(f, "Candid error: {e}"),
             BridgeError::TargetRejected(e) => write!(f, "Target canister rejected call: {e}"),
-            BridgeError::GuardError(e) => write!(f, "Payment guard error: {e}"),
+            BridgeError::TargetRejectedRefunded { reason, refunded } => write!(
+                f,
+                "Target canister rejected call: {reason}. Refunded {refunded} to the payer."
+            ),
+            BridgeError::RefundFailed {
+                reason,
+                refund,
+                error,
+            } => write!(
+                f,
+                "Target canister rejected call: {reason}. Failed to refund {refund} to the payer: {error}"
+            ),
+            BridgeError::OutcomeUnknown(e) => write!(
+                f,
+                "The outcome of the call is unknown; the target may have executed it: {e}"
+            ),
+            BridgeError::GuardError(e) => write!(f, "Payment guard error: {e:?}"),
             BridgeError::MethodNotConfigured { target, method } => write!(
                 f,
                 "No price is configured for method `{method}` on canister `{target}`. \
                  The wrapper operator must register it first."
             ),
+            BridgeError::MethodMisconfigured {
+                target,
+                method,
+                reason,
+            } => write!(
+                f,
+                "The price of method `{method}` on canister `{target}` is misconfigured: {reason}"
+            ),
             BridgeError::ForwardRequiresCyclePayment => write!(
                 f,
                 "This method forwards cycles, which requires a cycle-denominated payment type \
-                 (AttachedCycles, CallerPaysIcrc2Cycles or PatronPaysIcrc2Cycles); \
-                 token payments do not credit the wrapper's cycle balance."
+                 (AttachedCycles, CallerPaysIcrc2Cycles or PatronPaysIcrc2Cycles), \
+                 or a token whose ledger has a token-to-cycles rate configured."
             ),
+            BridgeError::FeeDoesNotCoverForwardedCycles {
+                fee_in_cycles,
+                forward_cycles,
+            } => write!(
+                f,
+                "The fee is worth {fee_in_cycles} cycles, which does not cover the \
+                 {forward_cycles} cycles this method forwards."
+            ),
+            BridgeError::InsufficientCyclesReserve { needed, available } => write!(
+                f,
+                "The wrapper's cycles reserve cannot fund this call: {needed} cycles needed, \
+                 {available} available."
+            ),
+            BridgeError::SelfCall => write!(f, "Self-calls are not allowed through the bridge."),
             BridgeError::ForbiddenTarget(e) => write!(f, "Forbidden target: {e}"),
+            BridgeError::TargetPaused { target } => {
+                write!(f, "Calls to canister `{target}` are paused by the operator.")
+            }
+            BridgeError::MethodPaused { target, method } => write!(
+                f,
+                "Calls to method `{method}` on canister `{target}` are paused by the operator."
+            ),
+            BridgeError::CircuitOpen { target, open_until } => write!(
+                f,
+                "Calls to canister `{target}` are suspended after repeated failures, until {open_until}."
+            ),
+            BridgeError::ModuleHashChanged {
+                target,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "Canister `{target}` runs module {}, not the approved module {}; calls are \
+                 refused until the operator approves it.",
+                actual.as_ref().map_or("(none)".to_string(), |actual| hex(actual)),
+                hex(expected)
+            ),
+            BridgeError::ModuleHashUnavailable { target, reason } => write!(
+                f,
+                "Could not check the code of canister `{target}`: {reason}"
+            ),
+            BridgeError::ForwardCyclesNotSelectable => write!(
+                f,
+                "This method forwards a fixed number of cycles; `forward_cycles` must not be set."
+            ),
+            BridgeError::ForwardCyclesOutOfRange {
+                requested,
+                min,
+                max,
+            } => write!(
+                f,
+                "Cannot forward {requested} cycles: this method forwards between {min} and {max} cycles."
+            ),
+            BridgeError::FeeExceedsMax { fee, max_fee } => write!(
+                f,
+                "The call costs {} ({:?}), more than the maximum of {} ({:?}).",
+                fee.amount, fee.denom, max_fee.amount, max_fee.denom
+            ),
+            BridgeError::PriceOverflow => write!(f, "The price of this call is too large."),
+            BridgeError::InvalidRequestId { max_bytes } => {
+                write!(f, "Request ids may be at most {max_bytes} bytes long.")
+            }
+            BridgeError::RequestInProgress => write!(
+                f,
+                "A call with this request id is still in progress; retry once it has completed."
+            ),
+            BridgeError::RequestIdReused { target, method } => write!(
+                f,
+                "This request id was already used for method `{method}` on canister `{target}`."
+            ),
+            BridgeError::RequestIdReusedWithOtherArgs => write!(
+                f,
+                "This request id was already used for a call with other arguments, payment, \
+                 forwarded cycles or fee cap."
+            ),
+            BridgeError::TooManyRequests { max_requests } => write!(
+                f,
+                "{max_requests} calls with request ids are still in progress; retry once they have completed."
+            ),
+            BridgeError::EmptyBatch => write!(f, "A batch must contain at least one call."),
+            BridgeError::BatchTooLarge { max_calls } => {
+                write!(f, "A batch may contain at most {max_calls} calls.")
+            }
+            BridgeError::FeeDenomMismatch { fee, payment } => match payment {
+                Some(payment) => write!(
+                    f,
+                    "A call is priced in {fee:?}, but the batch is paid in {payment:?}."
+                ),
+                None => write!(
+                    f,
+                    "A call is priced in {fee:?}, which this payment type cannot pay."
+                ),
+            },
+            BridgeError::CallTextDisabled => write!(
+                f,
+                "call_text is currently disabled due to a workspace dependency conflict with the \
+                 Candid parser. Please use call_blob instead."
+            ),
         }
     }
 }
@@ -46,3 +315,46 @@ impl From<candid::Error> for BridgeError {
         BridgeError::Candid(e.to_string())
     }
 }
+
+impl From<PaymentError> for BridgeError {
+    fn from(e: PaymentError) -> Self {
+        BridgeError::GuardError(Box::new(e))
+    }
+}
+
+/// Formats a module hash as lowercase hex.
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reject_code_from_u32() {
+        assert_eq!(RejectCode::from(1), RejectCode::SysFatal);
+        assert_eq!(RejectCode::from(4), RejectCode::CanisterReject);
+        assert_eq!(RejectCode::from(6), RejectCode::SysUnknown);
+        assert_eq!(RejectCode::from(42), RejectCode::Unrecognized(42));
+    }
+
+    #[test]
+    fn test_target_reject_display() {
+        let reject = TargetReject {
+            reject_code: Some(RejectCode::CanisterError),
+            message: "trapped".to_string(),
+            may_have_executed: true,
+        };
+        assert_eq!(
+            reject.to_string(),
+            "CanisterError - trapped (the target may have executed the call)"
+        );
+        let reject = TargetReject {
+            reject_code: None,
+            message: "out of cycles".to_string(),
+            may_have_executed: false,
+        };
+        assert_eq!(reject.to_string(), "out of cycles");
+    }
+}
@@ -4,8 +4,11 @@ use ic_papi_api::PaymentType;
 use ic_papi_guard::guards::any::VendorPaymentConfig;
 use serde::Deserialize;
 use serde_bytes::ByteBuf;
+use sha2::{Digest, Sha256};
 
-#[derive(Debug, CandidType, Deserialize, Clone, Eq, PartialEq)]
+use crate::domain::errors::BridgeError;
+
+#[derive(Debug, CandidType, Deserialize, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
 pub enum FeeDenom {
     Cycles,
     Icrc2 { ledger: Principal },
@@ -17,11 +20,582 @@ pub struct FeeSpec {
     pub denom: FeeDenom,
 }
 
+impl FeeSpec {
+    /// Whether this fee is at most `max`, in the same denomination.
+    #[must_use]
+    pub fn fits_within(&self, max: &FeeSpec) -> bool {
+        self.denom == max.denom && self.amount <= max.amount
+    }
+}
+
+/// What the wrapper gives back to the payer when the target rejects a proxied call.
+#[derive(Debug, CandidType, Deserialize, Clone, Eq, PartialEq, Default)]
+pub enum RefundPolicy {
+    /// The fee is kept by the wrapper.
+    #[default]
+    NoRefund,
+    /// The whole fee is returned to the payer.
+    Full,
+    /// The fee is returned to the payer, less a processing fee kept by the wrapper.
+    ///
+    /// The processing fee is in the same denomination as the method's fee.
+    MinusProcessingFee { processing_fee: u128 },
+}
+
+impl RefundPolicy {
+    /// The amount to return to the payer for a rejected call that charged `fee`.
+    #[must_use]
+    pub fn refund_amount(&self, fee: u128) -> u128 {
+        match self {
+            RefundPolicy::NoRefund => 0,
+            RefundPolicy::Full => fee,
+            RefundPolicy::MinusProcessingFee { processing_fee } => {
+                fee.saturating_sub(*processing_fee)
+            }
+        }
+    }
+
+    /// Whether this policy refunds less than `old` for some fee.
+    #[must_use]
+    pub fn refunds_less_than(&self, old: &RefundPolicy) -> bool {
+        match (self, old) {
+            (RefundPolicy::Full, _)
+            | (RefundPolicy::NoRefund, RefundPolicy::NoRefund)
+            | (RefundPolicy::MinusProcessingFee { .. }, RefundPolicy::NoRefund) => false,
+            (RefundPolicy::NoRefund, _) => true,
+            (RefundPolicy::MinusProcessingFee { processing_fee }, RefundPolicy::Full) => {
+                *processing_fee > 0
+            }
+            (
+                RefundPolicy::MinusProcessingFee { processing_fee },
+                RefundPolicy::MinusProcessingFee {
+                    processing_fee: old_fee,
+                },
+            ) => processing_fee > old_fee,
+        }
+    }
+}
+
+/// The exchange rate at which the wrapper's cycles reserve funds forwarded
+/// cycles for calls paid in a given token: `tokens` (in the ledger's smallest
+/// unit) are worth `cycles` cycles.
+#[derive(Debug, CandidType, Deserialize, Clone, Copy, Eq, PartialEq)]
+pub struct TokenCyclesRate {
+    pub tokens: u128,
+    pub cycles: u128,
+}
+
+impl TokenCyclesRate {
+    /// The value of `tokens` in cycles, rounded down.
+    #[must_use]
+    pub fn to_cycles(&self, tokens: u128) -> u128 {
+        mul_div(tokens, self.cycles, self.tokens, false)
+    }
+
+    /// The tokens needed to buy `cycles`, rounded up.
+    #[must_use]
+    pub fn to_tokens(&self, cycles: u128) -> u128 {
+        mul_div(cycles, self.tokens, self.cycles, true)
+    }
+}
+
+/// Computes `x * numerator / denominator`, saturating at `u128::MAX`.
+pub(crate) fn mul_div(x: u128, numerator: u128, denominator: u128, round_up: bool) -> u128 {
+    if denominator == 0 {
+        return u128::MAX;
+    }
+    let whole = (x / denominator).saturating_mul(numerator);
+    let remainder = x % denominator;
+    // `remainder < denominator`, so this only overflows for rates near `u128::MAX`.
+    let part = remainder.saturating_mul(numerator);
+    let part = if round_up {
+        part.div_ceil(denominator)
+    } else {
+        part / denominator
+    };
+    whole.saturating_add(part)
+}
+
+/// Accounting for the cycles the operator has set aside to fund forwarded
+/// cycles on calls paid in tokens.
+#[derive(Debug, CandidType, Deserialize, Clone, Copy, Default, Eq, PartialEq)]
+pub struct CyclesReserve {
+    /// Cycles available to fund forwarding.
+    pub balance: u128,
+    /// Cycles forwarded from the reserve and kept by targets, in total.
+    pub spent: u128,
+}
+
+/// A permission to change part of the wrapper's configuration.
+///
+/// Canister controllers implicitly hold every role, and an [`Role::Admin`]
+/// may do anything the other roles may.
+#[derive(Debug, CandidType, Deserialize, Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum Role {
+    /// Manages roles and the cycles reserve, and holds every other role.
+    Admin,
+    /// Sets prices, pricing rules and token-to-cycles rates.
+    PricingManager,
+    /// Pauses and resumes targets and methods, and manages the circuit breaker.
+    Operator,
+    /// Reads configuration that is not public, such as who holds which role.
+    Auditor,
+}
+
+/// The module an operator has approved for a target.
+#[derive(Debug, CandidType, Deserialize, Clone, Eq, PartialEq)]
+pub struct ModulePin {
+    /// The SHA-256 hash of the approved module.
+    pub hash: ByteBuf,
+    /// Set once the target was found running other code. Calls are refused
+    /// until the operator pins a hash again.
+    pub changed: Option<ModuleChange>,
+}
+
+/// A change of a pinned target's code.
+#[derive(Debug, CandidType, Deserialize, Clone, Eq, PartialEq)]
+pub struct ModuleChange {
+    /// The hash of the module found, or `None` if no code was installed.
+    pub observed: Option<ByteBuf>,
+    /// When the change was found, in nanoseconds since the epoch.
+    pub detected_at: u64,
+}
+
+/// What a configuration change applied to.
+#[derive(Debug, CandidType, Deserialize, Clone, Eq, PartialEq)]
+pub enum ConfigKey {
+    /// The price of a single `(target, method)` pair.
+    Method(MethodKey),
+    /// A pricing rule.
+    Rule(PricingRule),
+}
+
+/// An entry in the audit log of configuration changes.
+#[derive(Debug, CandidType, Deserialize, Clone, Eq, PartialEq)]
+pub struct ConfigChange {
+    /// The entry's position in the log, starting at 0. Positions are never
+    /// reused, even once the oldest entries are dropped.
+    pub seq: u64,
+    /// Who made the change.
+    pub actor: Principal,
+    /// When the change was made, in nanoseconds since the epoch.
+    pub timestamp: u64,
+    pub key: ConfigKey,
+    /// The configuration before the change; `None` if there was none.
+    pub old: Option<MethodConfig>,
+    /// The configuration after the change; `None` if it was removed.
+    pub new: Option<MethodConfig>,
+    /// For a scheduled change, when `new` takes effect, in nanoseconds since
+    /// the epoch. `None` if it took effect at `timestamp`.
+    pub effective_at: Option<u64>,
+}
+
+/// A page of the audit log.
+#[derive(Debug, CandidType, Deserialize, Clone, Eq, PartialEq)]
+pub struct ConfigChangePage {
+    pub changes: Vec<ConfigChange>,
+    /// The `seq` to ask for to get the next page, if there are more entries.
+    pub next: Option<u64>,
+}
+
+/// A price that will replace a method's or rule's current price at
+/// `effective_at`.
+#[derive(Debug, CandidType, Deserialize, Clone, Eq, PartialEq)]
+pub struct PendingConfig {
+    /// The new price, or `None` if the price is to be removed.
+    pub config: Option<MethodConfig>,
+    /// When the price takes effect, in nanoseconds since the epoch.
+    pub effective_at: u64,
+}
+
+/// The current and scheduled prices of a `(target, method)` pair.
+#[derive(Debug, CandidType, Deserialize, Clone, Default, Eq, PartialEq)]
+pub struct MethodPrices {
+    pub current: Option<MethodConfig>,
+    pub pending: Option<PendingConfig>,
+}
+
+/// The format version of [`ConfigExport`].
+pub const CONFIG_EXPORT_VERSION: u32 = 1;
+
+/// The wrapper's whole price registry, as moved between instances by
+/// `export_config` and `import_config`.
+#[derive(Debug, CandidType, Deserialize, Clone, Eq, PartialEq)]
+pub struct ConfigExport {
+    /// The format version; see [`CONFIG_EXPORT_VERSION`].
+    pub version: u32,
+    /// Prices of single `(target, method)` pairs.
+    pub methods: Vec<(MethodKey, MethodConfig)>,
+    pub rules: Vec<(PricingRule, MethodConfig)>,
+    /// SHA-256 of the Candid encoding of `(version, methods, rules)`, which
+    /// detects an export that was truncated or edited by mistake.
+    pub hash: ByteBuf,
+}
+
+impl ConfigExport {
+    /// Creates an export of the given prices and rules, in a canonical order.
+    #[must_use]
+    pub fn new(
+        mut methods: Vec<(MethodKey, MethodConfig)>,
+        mut rules: Vec<(PricingRule, MethodConfig)>,
+    ) -> Self {
+        methods.sort_by(|(a, _), (b, _)| (a.target, &a.method).cmp(&(b.target, &b.method)));
+        rules.sort_by(|(a, _), (b, _)| {
+            (a.target, &a.method_prefix).cmp(&(b.target, &b.method_prefix))
+        });
+        let mut export = Self {
+            version: CONFIG_EXPORT_VERSION,
+            methods,
+            rules,
+            hash: ByteBuf::new(),
+        };
+        export.hash = ByteBuf::from(export.compute_hash());
+        export
+    }
+
+    /// The hash that `hash` should hold.
+    #[must_use]
+    pub fn compute_hash(&self) -> Vec<u8> {
+        let bytes = Encode!(&self.version, &self.methods, &self.rules)
+            .expect("Failed to encode the configuration");
+        Sha256::digest(bytes).to_vec()
+    }
+}
+
+/// How `import_config` combines the imported prices with the current ones.
+#[derive(Debug, CandidType, Deserialize, Clone, Copy, Eq, PartialEq)]
+pub enum ImportMode {
+    /// The import becomes the whole registry: prices and rules it does not
+    /// contain are removed.
+    Replace,
+    /// Imported prices and rules are added or updated; others are kept.
+    Merge,
+}
+
+/// Arguments for the `import_config` function.
+#[derive(Debug, CandidType, Deserialize, Clone, Eq, PartialEq)]
+pub struct ImportConfigArgs {
+    pub config: ConfigExport,
+    pub mode: ImportMode,
+    /// If `true`, validate the import and report what it would change, without
+    /// changing anything.
+    pub dry_run: bool,
+}
+
+/// What an import changed, or would change in a dry run. Prices and rules are
+/// counted together.
+#[derive(Debug, CandidType, Deserialize, Clone, Copy, Default, Eq, PartialEq)]
+pub struct ImportSummary {
+    pub added: u64,
+    pub updated: u64,
+    pub removed: u64,
+    pub unchanged: u64,
+}
+
+/// Targets and methods that an operator has paused.
+#[derive(Debug, CandidType, Deserialize, Clone, Default, Eq, PartialEq)]
+pub struct Pauses {
+    /// Targets none of whose methods may be called.
+    pub targets: Vec<Principal>,
+    /// Individual methods that may not be called.
+    pub methods: Vec<MethodKey>,
+}
+
+/// Stops traffic to a target after repeated failures.
+#[derive(Debug, CandidType, Deserialize, Clone, Copy, Eq, PartialEq)]
+pub struct CircuitBreakerConfig {
+    /// The number of consecutive failed calls to a target that trips its breaker.
+    pub failure_threshold: u32,
+    /// The failures must all happen within this many seconds of the first.
+    pub window_seconds: u64,
+    /// How long a tripped breaker stays open before calls are allowed again.
+    pub cooldown_seconds: u64,
+}
+
+/// The circuit breaker of a target that has recently failed.
+#[derive(Debug, CandidType, Deserialize, Clone, Eq, PartialEq)]
+pub struct CircuitBreakerStatus {
+    pub target: Principal,
+    /// Consecutive failures counted towards tripping the breaker.
+    pub consecutive_failures: u32,
+    /// If the breaker is open, when it closes, in nanoseconds since the epoch.
+    pub open_until: Option<u64>,
+}
+
+/// Lets the caller choose how many cycles to forward, within operator-set bounds.
+///
+/// The caller is charged `fee.amount + forwarded + forwarded * markup_bps / 10_000`,
+/// so the fee always funds the forwarded cycles.
+#[derive(Debug, CandidType, Deserialize, Clone, Eq, PartialEq)]
+pub struct ForwardCyclesRange {
+    /// The fewest cycles a caller may forward; also used when the caller does not choose.
+    pub min: u128,
+    /// The most cycles a caller may forward.
+    pub max: u128,
+    /// Markup on the forwarded cycles, in basis points (1/100 of a percent).
+    pub markup_bps: u32,
+}
+
+/// Lets the wrapper answer repeated identical calls from a cache.
+///
+/// Only suitable for deterministic methods whose reply does not depend on the
+/// caller or on when they are called, within `ttl_seconds`.
+#[derive(Debug, CandidType, Deserialize, Clone, Eq, PartialEq)]
+pub struct CacheConfig {
+    /// How long a reply is served from the cache.
+    pub ttl_seconds: u64,
+    /// The fee charged for a reply served from the cache, in the method's fee
+    /// denomination. Must not exceed the method's fee.
+    pub hit_fee: u128,
+}
+
+/// Prices a call by the size of its arguments and reply, on top of the fee.
+///
+/// The reply's size is only known after the call, so the wrapper charges for
+/// a reply of `max_reply_bytes` up front and returns the difference once the
+/// reply has arrived.
+#[derive(Debug, CandidType, Deserialize, Clone, Eq, PartialEq)]
+pub struct SizePricing {
+    /// Charged per byte of the caller's Candid-encoded arguments.
+    pub per_request_byte: u128,
+    /// Charged per byte of the target's reply.
+    pub per_reply_byte: u128,
+    /// The largest reply charged for; larger replies cost the same.
+    pub max_reply_bytes: u64,
+}
+
+impl SizePricing {
+    /// The part of the fee charged for the arguments, and the part reserved
+    /// for the reply.
+    ///
+    /// # Errors
+    /// If the price does not fit in a `u128`.
+    pub fn charge(&self, request_bytes: u64) -> Result<(u128, u128), BridgeError> {
+        let request = self
+            .per_request_byte
+            .checked_mul(u128::from(request_bytes))
+            .ok_or(BridgeError::PriceOverflow)?;
+        let reply_reserve = self
+            .per_reply_byte
+            .checked_mul(u128::from(self.max_reply_bytes))
+            .ok_or(BridgeError::PriceOverflow)?;
+        Ok((request, reply_reserve))
+    }
+
+    /// What a reply of `reply_bytes` costs, out of the reserved amount.
+    #[must_use]
+    pub fn reply_cost(&self, reply_bytes: u64) -> u128 {
+        self.per_reply_byte
+            .saturating_mul(u128::from(reply_bytes.min(self.max_reply_bytes)))
+    }
+}
+
+/// How long the wrapper waits for the target to reply.
+#[derive(Debug, CandidType, Deserialize, Clone, Copy, Eq, PartialEq)]
+pub enum CallWait {
+    /// A best-effort call that gives up after `timeout_seconds` (by default
+    /// [`DEFAULT_CALL_TIMEOUT_SECONDS`]). On timeout the outcome is unknown: the
+    /// target may or may not have executed the call.
+    Bounded { timeout_seconds: Option<u32> },
+    /// A guaranteed-response call that waits for the target however long it
+    /// takes. The wrapper cannot be upgraded while such a call is outstanding.
+    Unbounded,
+}
+
+/// The timeout of a bounded-wait call unless the method sets one.
+pub const DEFAULT_CALL_TIMEOUT_SECONDS: u32 = 300;
+
+impl Default for CallWait {
+    fn default() -> Self {
+        CallWait::Bounded {
+            timeout_seconds: None,
+        }
+    }
+}
+
 #[derive(Debug, CandidType, Deserialize, Clone, Eq, PartialEq)]
 pub struct MethodConfig {
     pub fee: FeeSpec,
     pub supported: Vec<VendorPaymentConfig>,
     pub forward_cycles: Option<u128>,
+    /// Applied when the target rejects the call; `None` keeps the fee.
+    pub refund: Option<RefundPolicy>,
+    /// If set, the caller chooses the cycles to forward instead of `forward_cycles`.
+    pub forward_range: Option<ForwardCyclesRange>,
+    /// If set, identical calls are served from a cache at a lower price.
+    pub cache: Option<CacheConfig>,
+    /// How long to wait for the target; `None` is a bounded wait with the
+    /// default timeout.
+    pub wait: Option<CallWait>,
+    /// If `true`, the target receives a [`CallerEnvelope`] instead of the
+    /// caller's arguments, telling it who called and what they paid. All
+    /// prices on a target must agree on this, so that either every call to
+    /// it is wrapped or none is.
+    pub forward_caller: Option<bool>,
+    /// If set, the fee also grows with the size of the arguments and reply.
+    pub size_pricing: Option<SizePricing>,
+}
+
+/// The single argument a target receives in place of the caller's arguments,
+/// if its method is configured with `forward_caller`.
+///
+/// Only the wrapper can vouch for this: a target must ignore envelopes that do
+/// not come from a wrapper canister it trusts, or that name another target or
+/// method than the one called.
+#[derive(Debug, CandidType, Deserialize, Clone, Eq, PartialEq)]
+pub struct CallerEnvelope {
+    /// The principal that called the wrapper.
+    pub caller: Principal,
+    /// The canister the wrapper forwarded the call to.
+    pub target: Principal,
+    /// The method the wrapper forwarded the call to.
+    pub method: String,
+    /// What the caller paid for this call.
+    pub receipt: PaymentReceipt,
+    /// The caller's Candid-encoded arguments, unchanged.
+    pub args: ByteBuf,
+}
+
+/// What was paid for a forwarded call.
+#[derive(Debug, CandidType, Deserialize, Clone, Eq, PartialEq)]
+pub struct PaymentReceipt {
+    /// How the fee was paid.
+    pub payment: PaymentType,
+    /// The fee charged for this call. In a batch, this call's share of the
+    /// total.
+    pub fee: FeeSpec,
+    /// The cycles attached to this call.
+    pub forward_cycles: u128,
+    /// The caller's request id, if the call was made idempotent.
+    pub request_id: Option<String>,
+    /// When the wrapper forwarded the call, in nanoseconds since the epoch.
+    pub forwarded_at: u64,
+}
+
+/// What a single proxied call costs.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Charge {
+    /// The fee to deduct from the payer, in the method's fee denomination.
+    pub fee: u128,
+    /// The cycles to attach to the forwarded call.
+    pub forward_cycles: u128,
+}
+
+impl MethodConfig {
+    /// A configuration that charges `fee` cycles per call, with every optional
+    /// behaviour off.
+    #[must_use]
+    pub fn cycles(fee: u128) -> Self {
+        Self {
+            fee: FeeSpec {
+                amount: fee,
+                denom: FeeDenom::Cycles,
+            },
+            supported: vec![],
+            forward_cycles: None,
+            refund: None,
+            forward_range: None,
+            cache: None,
+            wait: None,
+            forward_caller: None,
+            size_pricing: None,
+        }
+    }
+
+    /// Whether calls priced by this configuration are wrapped in a
+    /// [`CallerEnvelope`].
+    #[must_use]
+    pub fn forwards_caller(&self) -> bool {
+        self.forward_caller == Some(true)
+    }
+
+    /// Whether switching from `old` to this configuration could make any call
+    /// cost more: a different fee denomination, a higher fee, a higher
+    /// markup on forwarded cycles, a higher or removed cache-hit fee, a higher
+    /// per-byte price or reply reservation, or a refund policy that gives
+    /// back less.
+    #[must_use]
+    pub fn raises_price_over(&self, old: &MethodConfig) -> bool {
+        let markup = |config: &MethodConfig| config.forward_range.as_ref().map(|r| r.markup_bps);
+        let hit_fee = |config: &MethodConfig| config.cache.as_ref().map(|c| c.hit_fee);
+        let size = |config: &MethodConfig| {
+            config.size_pricing.as_ref().map_or((0, 0, 0), |size| {
+                (
+                    size.per_request_byte,
+                    size.per_reply_byte,
+                    size.per_reply_byte
+                        .saturating_mul(u128::from(size.max_reply_bytes)),
+                )
+            })
+        };
+        let (new_size, old_size) = (size(self), size(old));
+        self.fee.denom != old.fee.denom
+            || new_size.0 > old_size.0
+            || new_size.1 > old_size.1
+            || new_size.2 > old_size.2
+            || self.fee.amount > old.fee.amount
+            || markup(self).unwrap_or(0) > markup(old).unwrap_or(0)
+            || self.forward_range.as_ref().map(|r| r.min)
+                > old.forward_range.as_ref().map(|r| r.min)
+            || match (hit_fee(self), hit_fee(old)) {
+                (Some(new), Some(old)) => new > old,
+                (None, Some(_)) => true,
+                (_, None) => false,
+            }
+            || self
+                .refund
+                .clone()
+                .unwrap_or_default()
+                .refunds_less_than(&old.refund.clone().unwrap_or_default())
+    }
+
+    /// Whether replacing the price `old` with `new` could make any call cost
+    /// more. Removing a price counts: a higher one could be set right after.
+    #[must_use]
+    pub fn change_raises_price(old: Option<&MethodConfig>, new: Option<&MethodConfig>) -> bool {
+        match (old, new) {
+            (Some(old), Some(new)) => new.raises_price_over(old),
+            (Some(_), None) => true,
+            (None, _) => false,
+        }
+    }
+
+    /// Computes the charge for a call, given the cycles the caller asked to forward.
+    ///
+    /// # Errors
+    /// - If the caller chose a forwarded amount but the method forwards a fixed amount.
+    /// - If the chosen amount is outside the method's [`ForwardCyclesRange`].
+    /// - If the price does not fit in a `u128`.
+    pub fn charge(&self, requested_cycles: Option<u128>) -> Result<Charge, BridgeError> {
+        let Some(range) = &self.forward_range else {
+            if requested_cycles.is_some() {
+                return Err(BridgeError::ForwardCyclesNotSelectable);
+            }
+            return Ok(Charge {
+                fee: self.fee.amount,
+                forward_cycles: self.forward_cycles.unwrap_or(0),
+            });
+        };
+        let forward_cycles = requested_cycles.unwrap_or(range.min);
+        if forward_cycles < range.min || forward_cycles > range.max {
+            return Err(BridgeError::ForwardCyclesOutOfRange {
+                requested: forward_cycles,
+                min: range.min,
+                max: range.max,
+            });
+        }
+        let markup = forward_cycles
+            .checked_mul(u128::from(range.markup_bps))
+            .map(|x| x / 10_000);
+        let fee = markup
+            .and_then(|markup| markup.checked_add(forward_cycles))
+            .and_then(|variable| variable.checked_add(self.fee.amount))
+            .ok_or(BridgeError::PriceOverflow)?;
+        Ok(Charge {
+            fee,
+            forward_cycles,
+        })
+    }
 }
 
 #[derive(Debug, CandidType, Deserialize, Clone, Eq, PartialEq, Hash)]
@@ -30,19 +604,58 @@ pub struct MethodKey {
     pub method: String,
 }
 
+/// A request id, scoped to the caller that chose it.
+pub type RequestKey = (Principal, String);
+
+/// A cached reply is keyed by the method and the SHA-256 hash of its arguments.
+pub type CacheKey = (MethodKey, [u8; 32]);
+
+/// The fee the cycles ledger charges for a transfer, unless a different one
+/// is recorded.
+pub const CYCLES_LEDGER_FEE: u128 = 100_000_000;
+
+/// Prices every method of `target` whose name starts with `method_prefix`.
+///
+/// An empty prefix matches all of the target's methods. A price set for an
+/// exact [`MethodKey`] takes precedence over any rule, and among rules the one
+/// with the longest matching prefix wins.
+#[derive(Debug, CandidType, Deserialize, Clone, Eq, PartialEq, Hash)]
+pub struct PricingRule {
+    pub target: Principal,
+    pub method_prefix: String,
+}
+
+impl PricingRule {
+    /// Whether the rule applies to `key`.
+    #[must_use]
+    pub fn matches(&self, key: &MethodKey) -> bool {
+        self.target == key.target && key.method.starts_with(&self.method_prefix)
+    }
+}
+
 /// Arguments for the `call0` function.
 ///
 /// Note: the fee and the cycles to forward are **not** caller-supplied; they are
 /// looked up from the operator-configured [`MethodConfig`] for `(target, method)`.
-/// The caller only chooses which supported payment type to pay with.
+/// The caller only chooses which supported payment type to pay with and, for
+/// methods with a [`ForwardCyclesRange`], how many cycles to forward.
 #[derive(Debug, CandidType, Deserialize, Clone, Eq, PartialEq)]
 pub struct Call0Args {
     /// The principal of the canister to call.
     pub target: Principal,
     /// The name of the method to call.
     pub method: String,
-    /// Optional payment configuration (defaults to `AttachedCycles`).
+    /// Optional payment configuration (defaults to the caller's payment
+    /// preference, or else `AttachedCycles`).
     pub payment: Option<PaymentType>,
+    /// Cycles to forward, for methods with a [`ForwardCyclesRange`] (defaults to its `min`).
+    pub forward_cycles: Option<u128>,
+    /// Makes the call idempotent: a retry with the same id returns the first
+    /// outcome without charging again.
+    pub request_id: Option<String>,
+    /// The most the caller is willing to pay. The call fails before charging
+    /// if the price is higher, or in a different denomination.
+    pub max_fee: Option<FeeSpec>,
 }
 
 /// Arguments for the `call_blob` function.
@@ -54,8 +667,17 @@ pub struct CallBlobArgs {
     pub method: String,
     /// The Candid-encoded arguments as a byte buffer.
     pub args_blob: ByteBuf,
-    /// Optional payment configuration (defaults to `AttachedCycles`).
+    /// Optional payment configuration (defaults to the caller's payment
+    /// preference, or else `AttachedCycles`).
     pub payment: Option<PaymentType>,
+    /// Cycles to forward, for methods with a [`ForwardCyclesRange`] (defaults to its `min`).
+    pub forward_cycles: Option<u128>,
+    /// Makes the call idempotent: a retry with the same id returns the first
+    /// outcome without charging again.
+    pub request_id: Option<String>,
+    /// The most the caller is willing to pay. The call fails before charging
+    /// if the price is higher, or in a different denomination.
+    pub max_fee: Option<FeeSpec>,
 }
 
 /// Arguments for the `call_text` function.
@@ -67,8 +689,131 @@ pub struct CallTextArgs {
     pub method: String,
     /// The Candid text representation of the arguments.
     pub args_text: String,
-    /// Optional payment configuration (defaults to `AttachedCycles`).
+    /// Optional payment configuration (defaults to the caller's payment
+    /// preference, or else `AttachedCycles`).
+    pub payment: Option<PaymentType>,
+    /// Cycles to forward, for methods with a [`ForwardCyclesRange`] (defaults to its `min`).
+    pub forward_cycles: Option<u128>,
+    /// Makes the call idempotent: a retry with the same id returns the first
+    /// outcome without charging again.
+    pub request_id: Option<String>,
+    /// The most the caller is willing to pay. The call fails before charging
+    /// if the price is higher, or in a different denomination.
+    pub max_fee: Option<FeeSpec>,
+}
+
+/// One call in a [`CallBatchArgs`].
+#[derive(Debug, CandidType, Deserialize, Clone, Eq, PartialEq)]
+pub struct BatchCall {
+    /// The principal of the canister to call.
+    pub target: Principal,
+    /// The name of the method to call.
+    pub method: String,
+    /// The Candid-encoded arguments.
+    pub args_blob: ByteBuf,
+    /// Cycles to forward, for methods with a [`ForwardCyclesRange`] (defaults to its `min`).
+    pub forward_cycles: Option<u128>,
+    /// The most the caller is willing to pay for this call; see [`CallBlobArgs::max_fee`].
+    pub max_fee: Option<FeeSpec>,
+}
+
+/// Arguments for the `call_batch` function.
+#[derive(Debug, CandidType, Deserialize, Clone, Eq, PartialEq)]
+pub struct CallBatchArgs {
+    /// The calls to make. The sum of their prices is charged once.
+    pub calls: Vec<BatchCall>,
+    /// Optional payment configuration (defaults to the caller's payment
+    /// preference, or else `AttachedCycles`).
     pub payment: Option<PaymentType>,
+    /// Whether to make the calls concurrently rather than one after another.
+    pub parallel: Option<bool>,
+}
+
+impl CallBatchArgs {
+    /// The individual calls, as they would be made through `call_blob`.
+    #[must_use]
+    pub fn into_bridge_calls(self) -> Vec<BridgeCallArgs> {
+        self.calls
+            .into_iter()
+            .map(|call| BridgeCallArgs {
+                target: call.target,
+                method: call.method,
+                args: call.args_blob.into_vec(),
+                payment: self.payment.clone(),
+                forward_cycles: call.forward_cycles,
+                request_id: None,
+                max_fee: call.max_fee,
+            })
+            .collect()
+    }
+}
+
+/// What a call would cost, as returned by `estimate_cost`.
+#[derive(Debug, CandidType, Deserialize, Clone, Eq, PartialEq)]
+pub struct CostEstimate {
+    /// The fee that would be charged.
+    pub fee: FeeSpec,
+    /// The cycles that would be forwarded to the target. They are paid for
+    /// out of the fee.
+    pub forward_cycles: u128,
+    /// The part of the fee reserved for the size of the reply. What a smaller
+    /// reply does not use is returned after the call.
+    pub reply_reserve: u128,
+    /// The fee the ledger charges the payer for the transfer, on top of `fee`.
+    ///
+    /// `None` for attached cycles, or if the wrapper does not know the fee of
+    /// the payment's ledger.
+    pub ledger_fee: Option<u128>,
+    /// The allowance the payer must approve for the wrapper: `fee` plus
+    /// `ledger_fee`. `None` if no allowance is needed, or the ledger fee is
+    /// unknown.
+    pub required_allowance: Option<u128>,
+    /// Whether the call would currently be accepted with this payment type.
+    pub payment_accepted: bool,
+    /// Why the payment type would be refused, if it would be.
+    pub rejection: Option<BridgeError>,
+}
+
+/// A successful reply from a proxied call.
+#[derive(Debug, CandidType, Deserialize, Clone, Eq, PartialEq)]
+pub struct BridgeReply {
+    /// The Candid-encoded reply of the target method.
+    pub reply: ByteBuf,
+    /// The cycles attached to the forwarded call.
+    pub cycles_forwarded: u128,
+    /// Forwarded cycles that the target did not accept.
+    ///
+    /// These are deposited back into the payer's cycles ledger account, with
+    /// any unused reply reservation, so the net cost of the forwarded cycles is
+    /// `cycles_forwarded - cycles_unused`. Together they are kept if they are
+    /// worth less than the ledger's fee.
+    pub cycles_unused: u128,
+    /// Set if the unused cycles could not be returned to the payer; they then
+    /// remain in the wrapper's balance.
+    pub cycles_unused_error: Option<String>,
+    /// The part of the fee reserved for the size of the reply but not used,
+    /// returned to the payer; 0 if it was kept with the unused cycles.
+    pub reply_reserve_returned: u128,
+    /// Set if the unused reply reservation could not be returned to the payer.
+    pub reply_reserve_error: Option<String>,
+    /// Whether the reply was served from the cache, without calling the target.
+    pub from_cache: bool,
+}
+
+/// The remembered outcome of a call made with a caller-supplied `request_id`.
+#[derive(Debug, CandidType, Deserialize, Clone, Eq, PartialEq)]
+pub struct StoredRequest {
+    /// The target of the original call; a retry must name the same one.
+    pub target: Principal,
+    /// The method of the original call; a retry must name the same one.
+    pub method: String,
+    /// The [`BridgeCallArgs::fingerprint`] of the original call; a retry must
+    /// match it.
+    pub fingerprint: ByteBuf,
+    /// When the original call was received, in nanoseconds since the epoch.
+    pub received_at: u64,
+    /// The outcome of the original call, or `None` while it is in flight.
+    pub outcome: Option<Result<BridgeReply, BridgeError>>,
 }
 
 /// Internal arguments for the bridge call logic.
@@ -78,6 +823,26 @@ pub struct BridgeCallArgs {
     pub method: String,
     pub args: Vec<u8>,
     pub payment: Option<PaymentType>,
+    pub forward_cycles: Option<u128>,
+    pub request_id: Option<String>,
+    pub max_fee: Option<FeeSpec>,
+}
+
+impl BridgeCallArgs {
+    /// The SHA-256 hash of what the caller asked for besides the target and
+    /// method: the arguments, the payment type, the forwarded cycles and the
+    /// fee cap.
+    #[must_use]
+    pub fn fingerprint(&self) -> [u8; 32] {
+        let bytes = Encode!(
+            &serde_bytes::Bytes::new(&self.args),
+            &self.payment,
+            &self.forward_cycles,
+            &self.max_fee
+        )
+        .expect("Failed to encode the call");
+        Sha256::digest(bytes).into()
+    }
 }
 
 impl From<Call0Args> for BridgeCallArgs {
@@ -87,6 +852,9 @@ impl From<Call0Args> for BridgeCallArgs {
             method: args.method,
             args: Encode!(&()).unwrap(),
             payment: args.payment,
+            forward_cycles: args.forward_cycles,
+            request_id: args.request_id,
+            max_fee: args.max_fee,
         }
     }
 }
@@ -98,6 +866,9 @@ impl From<CallBlobArgs> for BridgeCallArgs {
             method: args.method,
             args: args.args_blob.into_vec(),
             payment: args.payment,
+            forward_cycles: args.forward_cycles,
+            request_id: args.request_id,
+            max_fee: args.max_fee,
         }
     }
 }
@@ -110,6 +881,9 @@ impl From<CallTextArgs> for BridgeCallArgs {
             // Note: args_text is not used yet as call_text is disabled
             args: vec![],
             payment: args.payment,
+            forward_cycles: args.forward_cycles,
+            request_id: args.request_id,
+            max_fee: args.max_fee,
         }
     }
 }
@@ -125,6 +899,9 @@ mod tests {
             target: Principal::anonymous(),
             method: "test".to_string(),
             payment: Some(PaymentType::AttachedCycles),
+            forward_cycles: None,
+            request_id: None,
+            max_fee: None,
         };
         let bridge_args: BridgeCallArgs = args.clone().into();
         assert_eq!(bridge_args.target, args.target);
@@ -134,6 +911,49 @@ mod tests {
         assert_eq!(bridge_args.args, Encode!(&()).unwrap());
     }
 
+    #[test]
+    fn test_fingerprint() {
+        let args = BridgeCallArgs {
+            target: Principal::anonymous(),
+            method: "test".to_string(),
+            args: vec![1, 2, 3],
+            payment: None,
+            forward_cycles: None,
+            request_id: Some("req-1".to_string()),
+            max_fee: None,
+        };
+        // The request id, target and method are checked on their own.
+        let renamed = BridgeCallArgs {
+            request_id: Some("req-2".to_string()),
+            ..args.clone()
+        };
+        assert_eq!(renamed.fingerprint(), args.fingerprint());
+        let changes = [
+            BridgeCallArgs {
+                args: vec![1, 2],
+                ..args.clone()
+            },
+            BridgeCallArgs {
+                payment: Some(PaymentType::AttachedCycles),
+                ..args.clone()
+            },
+            BridgeCallArgs {
+                forward_cycles: Some(1),
+                ..args.clone()
+            },
+            BridgeCallArgs {
+                max_fee: Some(FeeSpec {
+                    amount: 1,
+                    denom: FeeDenom::Cycles,
+                }),
+                ..args.clone()
+            },
+        ];
+        for changed in changes {
+            assert_ne!(changed.fingerprint(), args.fingerprint());
+        }
+    }
+
     #[test]
     fn test_call_blob_to_bridge_args() {
         let blob = vec![1, 2, 3];
@@ -142,14 +962,58 @@ mod tests {
             method: "test_blob".to_string(),
             args_blob: ByteBuf::from(blob.clone()),
             payment: None,
+            forward_cycles: Some(1000),
+            request_id: Some("req-1".to_string()),
+            max_fee: Some(FeeSpec {
+                amount: 10,
+                denom: FeeDenom::Cycles,
+            }),
         };
         let bridge_args: BridgeCallArgs = args.clone().into();
         assert_eq!(bridge_args.target, args.target);
         assert_eq!(bridge_args.method, args.method);
         assert_eq!(bridge_args.payment, None);
+        assert_eq!(bridge_args.forward_cycles, Some(1000));
+        assert_eq!(bridge_args.request_id, args.request_id);
+        assert_eq!(bridge_args.max_fee, args.max_fee);
         assert_eq!(bridge_args.args, blob);
     }
 
+    #[test]
+    fn test_call_batch_to_bridge_args() {
+        let args = CallBatchArgs {
+            calls: vec![
+                BatchCall {
+                    target: Principal::anonymous(),
+                    method: "first".to_string(),
+                    args_blob: ByteBuf::from(vec![1]),
+                    forward_cycles: None,
+                    max_fee: None,
+                },
+                BatchCall {
+                    target: Principal::management_canister(),
+                    method: "second".to_string(),
+                    args_blob: ByteBuf::from(vec![2]),
+                    forward_cycles: Some(500),
+                    max_fee: None,
+                },
+            ],
+            payment: Some(PaymentType::CallerPaysIcrc2Cycles),
+            parallel: Some(true),
+        };
+        let calls = args.into_bridge_calls();
+        assert_eq!(calls.len(), 2);
+        assert_eq!(calls[0].method, "first");
+        assert_eq!(calls[0].args, vec![1]);
+        assert_eq!(calls[1].target, Principal::management_canister());
+        assert_eq!(calls[1].forward_cycles, Some(500));
+        // Every call is paid for by the batch's payment, and none is idempotent.
+        for call in &calls {
+            assert_eq!(call.payment, Some(PaymentType::CallerPaysIcrc2Cycles));
+            assert_eq!(call.request_id, None);
+        }
+    }
+
     #[test]
     fn test_call_text_to_bridge_args() {
         let args = CallTextArgs {
@@ -157,6 +1021,9 @@ mod tests {
             method: "test_text".to_string(),
             args_text: "(record { x = 42 })".to_string(),
             payment: None,
+            forward_cycles: None,
+            request_id: None,
+            max_fee: None,
         };
         let bridge_args: BridgeCallArgs = args.clone().into();
         assert_eq!(bridge_args.target, args.target);
@@ -165,4 +1032,243 @@ mod tests {
         // call_text currently does not use args_text, so args should be empty
         assert_eq!(bridge_args.args, Vec::<u8>::new());
     }
+
+    #[test]
+    fn test_pricing_rule_matches() {
+        let target = Principal::anonymous();
+        let key = |method: &str| MethodKey {
+            target,
+            method: method.to_string(),
+        };
+        let rule = |method_prefix: &str| PricingRule {
+            target,
+            method_prefix: method_prefix.to_string(),
+        };
+        assert!(rule("").matches(&key("anything")));
+        assert!(rule("get_").matches(&key("get_balance")));
+        assert!(!rule("get_").matches(&key("set_balance")));
+        let other = PricingRule {
+            target: Principal::management_canister(),
+            method_prefix: String::new(),
+        };
+        assert!(!other.matches(&key("get_balance")));
+    }
+
+    #[test]
+    fn test_config_export_is_canonical() {
+        let key = |method: &str| MethodKey {
+            target: Principal::anonymous(),
+            method: method.to_string(),
+        };
+        let config = ranged_config(0, 10, 0);
+        let a = ConfigExport::new(
+            vec![(key("b"), config.clone()), (key("a"), config.clone())],
+            vec![],
+        );
+        let b = ConfigExport::new(
+            vec![(key("a"), config.clone()), (key("b"), config.clone())],
+            vec![],
+        );
+        assert_eq!(a, b);
+        assert_eq!(a.methods[0].0, key("a"));
+        assert_eq!(a.hash.to_vec(), a.compute_hash());
+        let c = ConfigExport::new(vec![(key("a"), config)], vec![]);
+        assert_ne!(a.hash, c.hash);
+    }
+
+    #[test]
+    fn test_raises_price_over() {
+        let old = ranged_config(100, 1_000, 50);
+        assert!(!old.raises_price_over(&old));
+        let cheaper = MethodConfig {
+            forward_range: old.forward_range.clone(),
+            ..MethodConfig::cycles(50)
+        };
+        assert!(!cheaper.raises_price_over(&old));
+        assert!(old.raises_price_over(&cheaper));
+        assert!(ranged_config(100, 1_000, 51).raises_price_over(&old));
+        assert!(ranged_config(101, 1_000, 50).raises_price_over(&old));
+        assert!(!ranged_config(100, 2_000, 50).raises_price_over(&old));
+        let in_tokens = MethodConfig {
+            fee: FeeSpec {
+                amount: 1,
+                denom: FeeDenom::Icrc2 {
+                    ledger: Principal::anonymous(),
+                },
+            },
+            ..old.clone()
+        };
+        assert!(in_tokens.raises_price_over(&old));
+        let sized = |per_request_byte, per_reply_byte, max_reply_bytes| MethodConfig {
+            size_pricing: Some(SizePricing {
+                per_request_byte,
+                per_reply_byte,
+                max_reply_bytes,
+            }),
+            ..old.clone()
+        };
+        assert!(sized(1, 0, 0).raises_price_over(&old));
+        assert!(!old.raises_price_over(&sized(1, 0, 0)));
+        assert!(sized(1, 2, 100).raises_price_over(&sized(1, 1, 100)));
+        assert!(sized(1, 1, 101).raises_price_over(&sized(1, 1, 100)));
+        assert!(!sized(1, 1, 100).raises_price_over(&sized(2, 1, 200)));
+        let cached = MethodConfig {
+            cache: Some(CacheConfig {
+                ttl_seconds: 60,
+                hit_fee: 1,
+            }),
+            ..old.clone()
+        };
+        assert!(old.raises_price_over(&cached));
+        assert!(!cached.raises_price_over(&old));
+        let refunded = |refund| MethodConfig {
+            refund: Some(refund),
+            ..old.clone()
+        };
+        let minus = |processing_fee| refunded(RefundPolicy::MinusProcessingFee { processing_fee });
+        assert!(old.raises_price_over(&refunded(RefundPolicy::Full)));
+        assert!(minus(1).raises_price_over(&refunded(RefundPolicy::Full)));
+        assert!(minus(2).raises_price_over(&minus(1)));
+        assert!(!minus(1).raises_price_over(&minus(2)));
+        assert!(!minus(1).raises_price_over(&old));
+        assert!(!refunded(RefundPolicy::Full).raises_price_over(&minus(1)));
+    }
+
+    #[test]
+    fn test_removing_a_price_raises_it() {
+        let old = ranged_config(100, 1_000, 50);
+        assert!(MethodConfig::change_raises_price(Some(&old), None));
+        assert!(!MethodConfig::change_raises_price(None, Some(&old)));
+        assert!(!MethodConfig::change_raises_price(Some(&old), Some(&old)));
+    }
+
+    #[test]
+    fn test_size_pricing() {
+        let size = SizePricing {
+            per_request_byte: 2,
+            per_reply_byte: 3,
+            max_reply_bytes: 1_000,
+        };
+        assert_eq!(size.charge(10), Ok((20, 3_000)));
+        assert_eq!(size.reply_cost(0), 0);
+        assert_eq!(size.reply_cost(400), 1_200);
+        assert_eq!(size.reply_cost(5_000), 3_000);
+        let huge = SizePricing {
+            per_request_byte: u128::MAX,
+            ..size
+        };
+        assert_eq!(huge.charge(2), Err(BridgeError::PriceOverflow));
+    }
+
+    #[test]
+    fn test_fee_fits_within() {
+        let cycles = |amount| FeeSpec {
+            amount,
+            denom: FeeDenom::Cycles,
+        };
+        assert!(cycles(10).fits_within(&cycles(10)));
+        assert!(cycles(9).fits_within(&cycles(10)));
+        assert!(!cycles(11).fits_within(&cycles(10)));
+        let tokens = FeeSpec {
+            amount: 1,
+            denom: FeeDenom::Icrc2 {
+                ledger: Principal::anonymous(),
+            },
+        };
+        assert!(!tokens.fits_within(&cycles(10)));
+    }
+
+    #[test]
+    fn test_refund_amount() {
+        assert_eq!(RefundPolicy::NoRefund.refund_amount(1000), 0);
+        assert_eq!(RefundPolicy::Full.refund_amount(1000), 1000);
+        let policy = RefundPolicy::MinusProcessingFee {
+            processing_fee: 100,
+        };
+        assert_eq!(policy.refund_amount(1000), 900);
+        // A processing fee larger than the fee never produces a negative refund.
+        assert_eq!(policy.refund_amount(50), 0);
+    }
+
+    fn ranged_config(min: u128, max: u128, markup_bps: u32) -> MethodConfig {
+        MethodConfig {
+            forward_range: Some(ForwardCyclesRange {
+                min,
+                max,
+                markup_bps,
+            }),
+            ..MethodConfig::cycles(100)
+        }
+    }
+
+    #[test]
+    fn test_charge_fixed_forwarding() {
+        let config = MethodConfig {
+            forward_range: None,
+            forward_cycles: Some(500),
+            ..ranged_config(0, 0, 0)
+        };
+        assert_eq!(
+            config.charge(None),
+            Ok(Charge {
+                fee: 100,
+                forward_cycles: 500
+            })
+        );
+        assert!(matches!(
+            config.charge(Some(500)),
+            Err(BridgeError::ForwardCyclesNotSelectable)
+        ));
+    }
+
+    #[test]
+    fn test_charge_ranged_forwarding() {
+        let config = ranged_config(1_000, 10_000, 250);
+        // Defaults to the minimum.
+        assert_eq!(
+            config.charge(None),
+            Ok(Charge {
+                fee: 100 + 1_000 + 25,
+                forward_cycles: 1_000
+            })
+        );
+        assert_eq!(
+            config.charge(Some(10_000)),
+            Ok(Charge {
+                fee: 100 + 10_000 + 250,
+                forward_cycles: 10_000
+            })
+        );
+        assert!(matches!(
+            config.charge(Some(999)),
+            Err(BridgeError::ForwardCyclesOutOfRange { .. })
+        ));
+        assert!(matches!(
+            config.charge(Some(10_001)),
+            Err(BridgeError::ForwardCyclesOutOfRange { .. })
+        ));
+    }
+
+    #[test]
+    fn test_charge_overflow() {
+        let config = ranged_config(0, u128::MAX, 0);
+        assert!(matches!(
+            config.charge(Some(u128::MAX)),
+            Err(BridgeError::PriceOverflow)
+        ));
+    }
+
+    #[test]
+    fn test_token_cycles_rate() {
+        // 1 token unit buys 750_000 cycles.
+        let rate = TokenCyclesRate {
+            tokens: 4,
+            cycles: 3_000_000,
+        };
+        assert_eq!(rate.to_cycles(10), 7_500_000);
+        assert_eq!(rate.to_tokens(7_500_000), 10);
+        // Converting cycles to tokens rounds up so the reserve is never underpaid.
+        assert_eq!(rate.to_tokens(7_500_001), 11);
+        assert_eq!(rate.to_cycles(u128::MAX), u128::MAX);
+    }
 }
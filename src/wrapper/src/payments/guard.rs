@@ -0,0 +1,31 @@
+//! How the bridge takes fees.
+//!
+//! [`crate::api::call::Bridge`] charges through a [`PaymentGuard`]. The
+//! standalone wrapper canister uses the guard in [`super::guard_config`], and
+//! any [`AnyPaymentGuard`] can be used the same way.
+
+use ic_papi_api::{PaymentError, PaymentType};
+use ic_papi_guard::guards::any::PaymentGuard as AnyPaymentGuard;
+
+/// Takes the fee for a call from whoever pays for it.
+#[allow(async_fn_in_trait)]
+pub trait PaymentGuard {
+    /// Whether `payment` is accepted at all.
+    fn accepts(&self, payment: &PaymentType) -> bool;
+
+    /// Deducts `fee` from the payer of `payment`.
+    ///
+    /// # Errors
+    /// If the payment type is not accepted or the payment fails.
+    async fn deduct(&self, payment: PaymentType, fee: u128) -> Result<(), PaymentError>;
+}
+
+impl<const CAP: usize> PaymentGuard for AnyPaymentGuard<CAP> {
+    fn accepts(&self, payment: &PaymentType) -> bool {
+        self.config(payment.clone()).is_some()
+    }
+
+    async fn deduct(&self, payment: PaymentType, fee: u128) -> Result<(), PaymentError> {
+        AnyPaymentGuard::deduct(self, payment, fee).await
+    }
+}
@@ -1 +1,5 @@
+pub mod guard;
 pub mod guard_config;
+#[cfg(feature = "canister")]
+pub mod payout;
+pub mod refund;
@@ -0,0 +1,34 @@
+//! Pays target owners the revenue accumulated in their balances.
+//!
+//! Cycles are deposited into the owner's account on the cycles ledger, and
+//! tokens are transferred from this canister's account on their ledger.
+use ic_papi_api::Account;
+
+use crate::domain::types::FeeDenom;
+use crate::payments::refund::{deposit_cycles, transfer_tokens};
+use crate::state;
+
+/// Pays `amount` in `denom` to `to`, returning what `to` receives.
+///
+/// The ledger's transfer fee for tokens comes out of `amount`.
+///
+/// # Errors
+/// Returns a description of the failure if the ledger fee is unknown or
+/// exceeds `amount`, or if the ledger cannot be reached or refuses the
+/// transfer.
+pub async fn pay_out(denom: &FeeDenom, to: Account, amount: u128) -> Result<u128, String> {
+    match denom {
+        FeeDenom::Cycles => deposit_cycles(to, amount).await.map(|()| amount),
+        FeeDenom::Icrc2 { ledger } => {
+            let fee = state::get_ledger_fee(ledger).ok_or_else(|| {
+                format!("The transfer fee of ledger {ledger} is not known to the wrapper.")
+            })?;
+            let received = amount.checked_sub(fee).filter(|&x| x > 0).ok_or_else(|| {
+                format!("{amount} does not cover the transfer fee of ledger {ledger} ({fee}).")
+            })?;
+            transfer_tokens(*ledger, to, received)
+                .await
+                .map(|()| received)
+        }
+    }
+}
@@ -0,0 +1,82 @@
+//! Returns a fee (or part of it) to whoever paid it.
+//!
+//! Funds go back the way they came: cycles are deposited into the payer's
+//! account on the cycles ledger, and tokens are transferred back to the payer's
+//! account on the ledger they were paid on.
+use candid::{Nat, Principal};
+use ic_cdk::call::Call;
+use ic_cycles_ledger_client::{DepositArgs, DepositResult, TransferArgs};
+use ic_papi_api::{cycles::cycles_ledger_canister_id, Account, PaymentType};
+
+/// Returns `amount` to the payer of `payment`.
+///
+/// `caller` is the principal that made the proxied call; it is the payer for the
+/// `Caller*` and `AttachedCycles` payment types.
+///
+/// Attached cycles cannot be handed back once accepted, so they are deposited
+/// into the caller's cycles ledger account instead.
+///
+/// # Errors
+/// Returns a description of the failure if the ledger cannot be reached or
+/// refuses the transfer.
+pub async fn refund(payment: &PaymentType, caller: Principal, amount: u128) -> Result<(), String> {
+    if amount == 0 {
+        return Ok(());
+    }
+    match payment {
+        PaymentType::AttachedCycles | PaymentType::CallerPaysIcrc2Cycles => {
+            deposit_cycles(main_account(caller), amount).await
+        }
+        PaymentType::PatronPaysIcrc2Cycles(patron) => deposit_cycles(patron.clone(), amount).await,
+        PaymentType::CallerPaysIcrc2Tokens(payment_type) => {
+            transfer_tokens(payment_type.ledger, main_account(caller), amount).await
+        }
+        PaymentType::PatronPaysIcrc2Tokens(payment_type) => {
+            transfer_tokens(payment_type.ledger, payment_type.patron.clone(), amount).await
+        }
+        _ => Err("Refunds are not supported for this payment type.".to_string()),
+    }
+}
+
+fn main_account(owner: Principal) -> Account {
+    Account {
+        owner,
+        subaccount: None,
+    }
+}
+
+/// Deposits cycles from this canister's balance into `to` on the cycles ledger.
+pub(crate) async fn deposit_cycles(to: Account, amount: u128) -> Result<(), String> {
+    let ledger = cycles_ledger_canister_id();
+    Call::bounded_wait(ledger, "deposit")
+        .with_arg(DepositArgs { to, memo: None })
+        .with_cycles(amount)
+        .await
+        .map_err(|err| format!("Failed to reach cycles ledger at {ledger}: {err}"))?
+        .candid::<DepositResult>()
+        .map_err(|err| format!("Unexpected response from cycles ledger at {ledger}: {err}"))
+        .map(|_| ())
+}
+
+/// Transfers tokens from this canister's main account to `to` on `ledger`.
+///
+/// The ledger's transfer fee is paid by this canister on top of `amount`.
+pub(crate) async fn transfer_tokens(
+    ledger: Principal,
+    to: Account,
+    amount: u128,
+) -> Result<(), String> {
+    ic_cycles_ledger_client::Service(ledger)
+        .icrc1_transfer(&TransferArgs {
+            to,
+            fee: None,
+            memo: None,
+            from_subaccount: None,
+            created_at_time: None,
+            amount: Nat::from(amount),
+        })
+        .await
+        .map_err(|err| format!("Failed to reach ledger canister at {ledger}: {err}"))?
+        .map_err(|err| format!("Ledger canister at {ledger} refused the transfer: {err:?}"))
+        .map(|_| ())
+}
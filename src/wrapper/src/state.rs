@@ -4,54 +4,2048 @@
 //! [`MethodConfig`]. Only the wrapper's controllers may change this
 //! configuration; callers can never set their own fee or forwarded-cycle amount.
 //!
-//! State is held on the heap and persisted across upgrades in stable memory via
-//! the `pre_upgrade`/`post_upgrade` hooks in `lib.rs`.
+//! Calls paid in tokens may forward cycles from an operator-funded
+//! [`CyclesReserve`], converted at a per-ledger [`TokenCyclesRate`]. The fees
+//! that ledgers charge for transfers are recorded so that callers can be told
+//! how much allowance to approve.
+//!
+//! Outcomes of calls made with a caller-supplied request id are remembered for
+//! a bounded time and number of requests, so that retries are not charged twice.
+//!
+//! Replies of cacheable methods are kept in a bounded cache. The cache is not
+//! persisted across upgrades.
+//!
+//! Owners of target canisters may register them and price their methods
+//! themselves. The revenue of calls to a registered target, less the platform
+//! fee, accrues to its owner's balance.
+//!
+//! Roles granted to principals decide who may change which part of the
+//! configuration.
+//!
+//! A price change may be scheduled to take effect later; it is applied the
+//! first time the wrapper handles a call or configuration change after it is
+//! due.
+//!
+//! Every change to a price or pricing rule is appended to an audit log, which
+//! is never pruned.
+//!
+//! Operators may pause targets and methods. A per-target circuit breaker
+//! suspends calls to a target after repeated failures; its state is not
+//! persisted across upgrades, but its configuration is.
+//!
+//! Operators may pin the module hash of a target. Once the target is found
+//! running other code, calls to it are refused until the operator pins a hash
+//! again.
+//!
+//! Callers may choose the payment type used when they do not name one.
+//!
+//! Persisted state lives in stable structures, each in its own virtual memory,
+//! with keys and values stored in versioned schemas (see
+//! [`crate::util::storable`]). It survives upgrades as it is, without being
+//! saved and restored by upgrade hooks. The reply cache and the circuit
+//! breakers' state are held on the heap.
 
-use crate::domain::types::{MethodConfig, MethodKey};
+use crate::domain::errors::BridgeError;
+use crate::domain::types::{
+    mul_div, BridgeReply, CacheKey, CircuitBreakerConfig, CircuitBreakerStatus, ConfigChange,
+    ConfigChangePage, ConfigKey, CyclesReserve, FeeDenom, MethodConfig, MethodKey, ModuleChange,
+    ModulePin, Pauses, PendingConfig, PricingRule, RequestKey, Role, StoredRequest,
+    TokenCyclesRate, CYCLES_LEDGER_FEE,
+};
+use crate::util::storable::{Key, Stored, Versioned};
+use candid::{CandidType, Deserialize, Principal};
+use ic_papi_api::cycles::cycles_ledger_canister_id;
+use ic_papi_api::PaymentType;
+use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
+use ic_stable_structures::{DefaultMemoryImpl, Memory, StableBTreeMap, StableBTreeSet, StableCell};
+use serde_bytes::ByteBuf;
 use std::cell::RefCell;
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap};
+
+/// How long the outcome of a call with a request id is remembered: 24 hours.
+pub const REQUEST_TTL_NANOS: u64 = 24 * 60 * 60 * 1_000_000_000;
+/// The most request outcomes remembered at once; the oldest completed one is
+/// forgotten first.
+pub const MAX_STORED_REQUESTS: usize = 10_000;
+/// The most request outcomes remembered for one caller; the caller's oldest
+/// completed one is forgotten first.
+pub const MAX_REQUESTS_PER_CALLER: usize = 100;
+/// The longest accepted request id, in bytes.
+pub const MAX_REQUEST_ID_BYTES: usize = 64;
+
+/// How far ahead a price increase must be scheduled, unless an admin changes
+/// it: 24 hours.
+pub const DEFAULT_MIN_INCREASE_NOTICE_NANOS: u64 = 24 * 60 * 60 * 1_000_000_000;
+
+/// The share of owners' revenue the wrapper keeps, unless an admin changes
+/// it: 5%.
+pub const DEFAULT_PLATFORM_FEE_BPS: u32 = 500;
+
+/// The most callers whose payment preferences are kept.
+pub const MAX_PAYMENT_PREFERENCES: u64 = 100_000;
+/// How long a payment preference is kept without being used: 90 days.
+pub const PAYMENT_PREFERENCE_TTL_NANOS: u64 = 90 * 24 * 60 * 60 * 1_000_000_000;
+/// How often the use of a payment preference is recorded: at most daily, so
+/// that calls need not write to stable memory to keep it alive.
+const PAYMENT_PREFERENCE_USE_INTERVAL_NANOS: u64 = 24 * 60 * 60 * 1_000_000_000;
+
+/// How long a target's module hash, once found to match its pin, is trusted
+/// without asking the management canister again: 60 seconds.
+pub const MODULE_CHECK_TTL_NANOS: u64 = 60 * 1_000_000_000;
+
+/// The most replies cached at once.
+pub const MAX_CACHED_REPLIES: usize = 1_000;
+/// The most reply bytes cached at once: 16 MiB.
+pub const MAX_CACHED_BYTES: usize = 16 * 1024 * 1024;
+
+struct CachedReply {
+    reply: Vec<u8>,
+    /// When the reply stops being served, in nanoseconds since the epoch.
+    expires_at: u64,
+}
+
+/// Failures of forwarded calls to one target.
+#[derive(Clone, Copy)]
+struct Breaker {
+    consecutive_failures: u32,
+    /// When the current run of failures began.
+    first_failure_at: u64,
+    /// If tripped, when calls are allowed again.
+    open_until: Option<u64>,
+}
+
+/// The wrapper's settings that are single values rather than collections.
+#[derive(CandidType, Deserialize, Clone, Copy)]
+struct Settings {
+    min_increase_notice_nanos: u64,
+    reserve: CyclesReserve,
+    breaker: Option<CircuitBreakerConfig>,
+    platform_fee_bps: u32,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            min_increase_notice_nanos: DEFAULT_MIN_INCREASE_NOTICE_NANOS,
+            reserve: CyclesReserve::default(),
+            breaker: None,
+            platform_fee_bps: DEFAULT_PLATFORM_FEE_BPS,
+        }
+    }
+}
+
+impl Versioned for Settings {}
+
+type VMem = VirtualMemory<DefaultMemoryImpl>;
+type Map<K, V> = StableBTreeMap<Key<K>, Stored<V>, VMem>;
+type Set<K> = StableBTreeSet<Key<K>, VMem>;
+
+// Each stable structure has its own memory. Never reuse the id of a structure
+// that has been dropped: its old contents would be read as the new one's.
+const SETTINGS_MEMORY: MemoryId = MemoryId::new(0);
+const CONFIGS_MEMORY: MemoryId = MemoryId::new(1);
+const PENDING_MEMORY: MemoryId = MemoryId::new(2);
+const RULES_MEMORY: MemoryId = MemoryId::new(3);
+const AUDIT_LOG_MEMORY: MemoryId = MemoryId::new(4);
+const ROLES_MEMORY: MemoryId = MemoryId::new(5);
+const RATES_MEMORY: MemoryId = MemoryId::new(6);
+const LEDGER_FEES_MEMORY: MemoryId = MemoryId::new(7);
+const TARGET_OWNERS_MEMORY: MemoryId = MemoryId::new(8);
+const OWNER_BALANCES_MEMORY: MemoryId = MemoryId::new(9);
+const REQUESTS_MEMORY: MemoryId = MemoryId::new(10);
+const REQUEST_QUEUE_MEMORY: MemoryId = MemoryId::new(11);
+const PAUSED_TARGETS_MEMORY: MemoryId = MemoryId::new(12);
+const PAUSED_METHODS_MEMORY: MemoryId = MemoryId::new(13);
+const MODULE_PINS_MEMORY: MemoryId = MemoryId::new(14);
+const PAYMENT_PREFERENCES_MEMORY: MemoryId = MemoryId::new(15);
+const COMPLETED_QUEUE_MEMORY: MemoryId = MemoryId::new(16);
+const CALLER_REQUESTS_MEMORY: MemoryId = MemoryId::new(17);
+const PENDING_RULES_MEMORY: MemoryId = MemoryId::new(18);
+const PENDING_OWNERS_MEMORY: MemoryId = MemoryId::new(19);
+const PREFERENCE_USES_MEMORY: MemoryId = MemoryId::new(20);
+const PREFERENCE_QUEUE_MEMORY: MemoryId = MemoryId::new(21);
 
 thread_local! {
-    static CONFIGS: RefCell<HashMap<MethodKey, MethodConfig>> = RefCell::new(HashMap::new());
+    static MEMORY_MANAGER: MemoryManager<DefaultMemoryImpl> = MemoryManager::init(DefaultMemoryImpl::default());
+    static SETTINGS: RefCell<StableCell<Stored<Settings>, VMem>> =
+        RefCell::new(StableCell::init(memory(SETTINGS_MEMORY), Stored(Settings::default())));
+    static CONFIGS: RefCell<Map<MethodKey, MethodConfig>> = RefCell::new(Map::init(memory(CONFIGS_MEMORY)));
+    static PENDING: RefCell<Map<MethodKey, PendingConfig>> = RefCell::new(Map::init(memory(PENDING_MEMORY)));
+    static RULES: RefCell<Map<PricingRule, MethodConfig>> = RefCell::new(Map::init(memory(RULES_MEMORY)));
+    static PENDING_RULES: RefCell<Map<PricingRule, PendingConfig>> =
+        RefCell::new(Map::init(memory(PENDING_RULES_MEMORY)));
+    static AUDIT_LOG: RefCell<StableBTreeMap<u64, Stored<ConfigChange>, VMem>> =
+        RefCell::new(StableBTreeMap::init(memory(AUDIT_LOG_MEMORY)));
+    static ROLES: RefCell<Map<Principal, BTreeSet<Role>>> = RefCell::new(Map::init(memory(ROLES_MEMORY)));
+    static RATES: RefCell<Map<Principal, TokenCyclesRate>> = RefCell::new(Map::init(memory(RATES_MEMORY)));
+    static LEDGER_FEES: RefCell<Map<Principal, u128>> = RefCell::new(Map::init(memory(LEDGER_FEES_MEMORY)));
+    static TARGET_OWNERS: RefCell<Map<Principal, Principal>> = RefCell::new(Map::init(memory(TARGET_OWNERS_MEMORY)));
+    static PENDING_OWNERS: RefCell<Map<Principal, Principal>> =
+        RefCell::new(Map::init(memory(PENDING_OWNERS_MEMORY)));
+    static OWNER_BALANCES: RefCell<Map<(Principal, FeeDenom), u128>> =
+        RefCell::new(Map::init(memory(OWNER_BALANCES_MEMORY)));
+    static REQUESTS: RefCell<Map<RequestKey, StoredRequest>> = RefCell::new(Map::init(memory(REQUESTS_MEMORY)));
+    /// The remembered requests, oldest first (see [`queue_entry`]).
+    static REQUEST_QUEUE: RefCell<StableBTreeSet<Vec<u8>, VMem>> =
+        RefCell::new(StableBTreeSet::init(memory(REQUEST_QUEUE_MEMORY)));
+    /// The remembered requests that have completed, oldest first.
+    static COMPLETED_QUEUE: RefCell<StableBTreeSet<Vec<u8>, VMem>> =
+        RefCell::new(StableBTreeSet::init(memory(COMPLETED_QUEUE_MEMORY)));
+    /// The remembered requests of each caller, oldest first (see
+    /// [`caller_entry`]).
+    static CALLER_REQUESTS: RefCell<StableBTreeSet<Vec<u8>, VMem>> =
+        RefCell::new(StableBTreeSet::init(memory(CALLER_REQUESTS_MEMORY)));
+    static CACHE: RefCell<HashMap<CacheKey, CachedReply>> = RefCell::new(HashMap::new());
+    static PAUSED_TARGETS: RefCell<Set<Principal>> = RefCell::new(Set::init(memory(PAUSED_TARGETS_MEMORY)));
+    static PAUSED_METHODS: RefCell<Set<MethodKey>> = RefCell::new(Set::init(memory(PAUSED_METHODS_MEMORY)));
+    static BREAKERS: RefCell<HashMap<Principal, Breaker>> = RefCell::new(HashMap::new());
+    static MODULE_PINS: RefCell<Map<Principal, ModulePin>> = RefCell::new(Map::init(memory(MODULE_PINS_MEMORY)));
+    /// The module hash last found on each pinned target to match its pin, and
+    /// when.
+    static MODULE_CHECKS: RefCell<HashMap<Principal, (Vec<u8>, u64)>> = RefCell::new(HashMap::new());
+    static PAYMENT_PREFERENCES: RefCell<Map<Principal, PaymentType>> =
+        RefCell::new(Map::init(memory(PAYMENT_PREFERENCES_MEMORY)));
+    /// When each payment preference was last set or used.
+    static PREFERENCE_USES: RefCell<Map<Principal, u64>> =
+        RefCell::new(Map::init(memory(PREFERENCE_USES_MEMORY)));
+    /// The payment preferences, least recently used first (see
+    /// [`preference_entry`]).
+    static PREFERENCE_QUEUE: RefCell<StableBTreeSet<Vec<u8>, VMem>> =
+        RefCell::new(StableBTreeSet::init(memory(PREFERENCE_QUEUE_MEMORY)));
+}
+
+fn memory(id: MemoryId) -> VMem {
+    MEMORY_MANAGER.with(|m| m.get(id))
+}
+
+fn entries<K, V>(map: &Map<K, V>) -> Vec<(K, V)>
+where
+    K: Versioned,
+    V: Versioned,
+{
+    map.iter()
+        .map(|entry| {
+            let (key, Stored(value)) = entry.into_pair();
+            (key.get(), value)
+        })
+        .collect()
+}
+
+fn replace_entries<K, V>(map: &mut Map<K, V>, items: impl IntoIterator<Item = (K, V)>)
+where
+    K: Versioned,
+    V: Versioned,
+{
+    map.clear_new();
+    for (key, value) in items {
+        map.insert(Key::new(&key), Stored(value));
+    }
+}
+
+fn settings() -> Settings {
+    SETTINGS.with(|s| s.borrow().get().0)
+}
+
+fn update_settings<R>(f: impl FnOnce(&mut Settings) -> R) -> R {
+    SETTINGS.with(|s| {
+        let mut cell = s.borrow_mut();
+        let mut settings = cell.get().0;
+        let result = f(&mut settings);
+        cell.set(Stored(settings));
+        result
+    })
+}
+
+/// Whether stable memory holds state saved by an older version of the
+/// wrapper, which serialized its heap state in `pre_upgrade`, rather than the
+/// stable structures of this version.
+///
+/// Must be called before any state is read or written: the first access lays
+/// the stable structures over whatever stable memory holds.
+#[must_use]
+pub fn holds_legacy_state() -> bool {
+    let memory = DefaultMemoryImpl::default();
+    if memory.size() == 0 {
+        return false;
+    }
+    let mut magic = [0; 3];
+    memory.read(0, &mut magic);
+    &magic != b"MGR"
 }
 
 /// Look up the operator configuration for a `(target, method)` pair.
 #[must_use]
 pub fn get_config(key: &MethodKey) -> Option<MethodConfig> {
-    CONFIGS.with(|c| c.borrow().get(key).cloned())
+    CONFIGS.with(|c| c.borrow().get(&Key::new(key)).map(|v| v.0))
 }
 
-/// Insert or replace the configuration for a `(target, method)` pair.
-pub fn set_config(key: MethodKey, config: MethodConfig) {
+/// Insert or replace the configuration for a `(target, method)` pair,
+/// returning any prior value.
+pub fn set_config(key: MethodKey, config: MethodConfig) -> Option<MethodConfig> {
     CONFIGS.with(|c| {
-        c.borrow_mut().insert(key, config);
-    });
+        c.borrow_mut()
+            .insert(Key::new(&key), Stored(config))
+            .map(|v| v.0)
+    })
 }
 
 /// Remove the configuration for a `(target, method)` pair, returning any prior value.
 #[must_use]
 pub fn remove_config(key: &MethodKey) -> Option<MethodConfig> {
-    CONFIGS.with(|c| c.borrow_mut().remove(key))
+    CONFIGS.with(|c| c.borrow_mut().remove(&Key::new(key)).map(|v| v.0))
 }
 
 /// Snapshot of all configured `(target, method)` prices.
 #[must_use]
 pub fn list_configs() -> Vec<(MethodKey, MethodConfig)> {
-    CONFIGS.with(|c| {
+    CONFIGS.with(|c| entries(&c.borrow()))
+}
+
+/// Replace the whole registry (used when migrating state saved by the first
+/// release).
+pub fn replace_all(items: Vec<(MethodKey, MethodConfig)>) {
+    CONFIGS.with(|c| replace_entries(&mut c.borrow_mut(), items));
+}
+
+/// Schedule `pending` to replace the price of `key`, replacing any change
+/// already scheduled for it.
+pub fn schedule_config(key: MethodKey, pending: PendingConfig) {
+    PENDING.with(|p| {
+        p.borrow_mut().insert(Key::new(&key), Stored(pending));
+    });
+}
+
+/// Cancel the change scheduled for `key`, returning it.
+#[must_use]
+pub fn cancel_pending_config(key: &MethodKey) -> Option<PendingConfig> {
+    PENDING.with(|p| p.borrow_mut().remove(&Key::new(key)).map(|v| v.0))
+}
+
+/// The change scheduled for `key`, if any.
+#[must_use]
+pub fn get_pending_config(key: &MethodKey) -> Option<PendingConfig> {
+    PENDING.with(|p| p.borrow().get(&Key::new(key)).map(|v| v.0))
+}
+
+/// Snapshot of all scheduled changes.
+#[must_use]
+pub fn list_pending_configs() -> Vec<(MethodKey, PendingConfig)> {
+    PENDING.with(|p| entries(&p.borrow()))
+}
+
+/// Take the changes in `pending` that are due at `now`.
+fn take_due<K: Versioned>(
+    pending: &mut Map<K, PendingConfig>,
+    now: u64,
+) -> Vec<(K, PendingConfig)> {
+    let keys: Vec<Key<K>> = pending
+        .iter()
+        .filter(|entry| entry.value().0.effective_at <= now)
+        .map(|entry| entry.key().clone())
+        .collect();
+    keys.into_iter()
+        .filter_map(|key| pending.remove(&key).map(|change| (key.get(), change.0)))
+        .collect()
+}
+
+/// Apply every scheduled change, to prices and pricing rules, that is due at
+/// `now`.
+pub fn apply_due_configs(now: u64) {
+    for (key, change) in PENDING.with(|p| take_due(&mut p.borrow_mut(), now)) {
+        clear_cached_replies(&key);
+        match change.config {
+            Some(config) => set_config(key, config),
+            None => remove_config(&key),
+        };
+    }
+    for (rule, change) in PENDING_RULES.with(|p| take_due(&mut p.borrow_mut(), now)) {
+        clear_cached_replies_for_rule(&rule);
+        match change.config {
+            Some(config) => set_rule(rule, config),
+            None => remove_rule(&rule),
+        };
+    }
+}
+
+/// How far ahead, in nanoseconds, a price increase must be scheduled.
+#[must_use]
+pub fn get_min_increase_notice() -> u64 {
+    settings().min_increase_notice_nanos
+}
+
+/// Set how far ahead, in nanoseconds, a price increase must be scheduled.
+pub fn set_min_increase_notice(nanos: u64) {
+    update_settings(|s| s.min_increase_notice_nanos = nanos);
+}
+
+/// The price that applies to a `(target, method)` pair: its own configuration
+/// if it has one, otherwise that of the matching rule with the longest prefix.
+#[must_use]
+pub fn resolve_config(key: &MethodKey) -> Option<MethodConfig> {
+    get_config(key).or_else(|| resolve_rule(key))
+}
+
+/// The price that the pricing rules give a `(target, method)` pair: that of
+/// the matching rule with the longest prefix.
+#[must_use]
+pub fn resolve_rule(key: &MethodKey) -> Option<MethodConfig> {
+    longest_rule(|rule| rule.matches(key))
+}
+
+/// The price that the methods `rule` covers get from the pricing rules, and
+/// that a change to `rule` would replace: that of `rule` itself, or else of
+/// the broader rule they fall under.
+#[must_use]
+pub fn rule_price(rule: &PricingRule) -> Option<MethodConfig> {
+    longest_rule(|other| {
+        other.target == rule.target && rule.method_prefix.starts_with(&other.method_prefix)
+    })
+}
+
+/// The price that the methods `rule` covers would get if `rule` were removed:
+/// that of the broader rule they fall under.
+#[must_use]
+pub fn price_without_rule(rule: &PricingRule) -> Option<MethodConfig> {
+    longest_rule(|other| {
+        other.target == rule.target
+            && other.method_prefix.len() < rule.method_prefix.len()
+            && rule.method_prefix.starts_with(&other.method_prefix)
+    })
+}
+
+/// Whether each price on `target` other than `except`, including scheduled
+/// ones, wraps calls in a caller envelope.
+#[must_use]
+pub fn envelope_settings(target: &Principal, except: &ConfigKey) -> Vec<bool> {
+    let methods = list_configs()
+        .into_iter()
+        .map(|(key, config)| (ConfigKey::Method(key), Some(config)))
+        .chain(
+            list_pending_configs()
+                .into_iter()
+                .map(|(key, pending)| (ConfigKey::Method(key), pending.config)),
+        );
+    let rules = list_rules()
+        .into_iter()
+        .map(|(rule, config)| (ConfigKey::Rule(rule), Some(config)))
+        .chain(
+            list_pending_rules()
+                .into_iter()
+                .map(|(rule, pending)| (ConfigKey::Rule(rule), pending.config)),
+        );
+    methods
+        .chain(rules)
+        .filter_map(|(key, config)| {
+            let on_target = match &key {
+                ConfigKey::Method(key) => key.target == *target,
+                ConfigKey::Rule(rule) => rule.target == *target,
+            };
+            (on_target && key != *except).then_some(config?.forwards_caller())
+        })
+        .collect()
+}
+
+/// The price of the rule with the longest prefix among those that `filter`
+/// accepts.
+fn longest_rule(filter: impl Fn(&PricingRule) -> bool) -> Option<MethodConfig> {
+    RULES.with(|r| {
+        r.borrow()
+            .iter()
+            .filter_map(|entry| {
+                let rule = entry.key().get();
+                filter(&rule).then(|| (rule.method_prefix.len(), entry.value().0))
+            })
+            .max_by_key(|(prefix_len, _)| *prefix_len)
+            .map(|(_, config)| config)
+    })
+}
+
+/// Insert or replace a pricing rule, returning any prior value.
+pub fn set_rule(rule: PricingRule, config: MethodConfig) -> Option<MethodConfig> {
+    RULES.with(|r| {
+        r.borrow_mut()
+            .insert(Key::new(&rule), Stored(config))
+            .map(|v| v.0)
+    })
+}
+
+/// Remove a pricing rule, returning any prior value.
+#[must_use]
+pub fn remove_rule(rule: &PricingRule) -> Option<MethodConfig> {
+    RULES.with(|r| r.borrow_mut().remove(&Key::new(rule)).map(|v| v.0))
+}
+
+/// The current price of a pricing rule, if it exists.
+#[must_use]
+pub fn get_rule(rule: &PricingRule) -> Option<MethodConfig> {
+    RULES.with(|r| r.borrow().get(&Key::new(rule)).map(|v| v.0))
+}
+
+/// Snapshot of all pricing rules.
+#[must_use]
+pub fn list_rules() -> Vec<(PricingRule, MethodConfig)> {
+    RULES.with(|r| entries(&r.borrow()))
+}
+
+/// Schedule `pending` to replace the price of `rule`, replacing any change
+/// already scheduled for it.
+pub fn schedule_rule(rule: PricingRule, pending: PendingConfig) {
+    PENDING_RULES.with(|p| {
+        p.borrow_mut().insert(Key::new(&rule), Stored(pending));
+    });
+}
+
+/// Cancel the change scheduled for `rule`, returning it.
+#[must_use]
+pub fn cancel_pending_rule(rule: &PricingRule) -> Option<PendingConfig> {
+    PENDING_RULES.with(|p| p.borrow_mut().remove(&Key::new(rule)).map(|v| v.0))
+}
+
+/// Snapshot of all scheduled pricing rule changes.
+#[must_use]
+pub fn list_pending_rules() -> Vec<(PricingRule, PendingConfig)> {
+    PENDING_RULES.with(|p| entries(&p.borrow()))
+}
+
+/// The most audit log entries returned in one page.
+pub const MAX_CONFIG_CHANGES_PAGE: usize = 100;
+/// The most audit log entries kept; the oldest are dropped first.
+pub const MAX_CONFIG_CHANGES: u64 = 10_000;
+
+/// Append a change to the audit log, unless it changed nothing. Beyond
+/// [`MAX_CONFIG_CHANGES`] entries, the oldest is dropped, and `seq` keeps
+/// counting from the newest.
+pub fn record_config_change(
+    actor: Principal,
+    timestamp: u64,
+    key: ConfigKey,
+    old: Option<MethodConfig>,
+    new: Option<MethodConfig>,
+    effective_at: Option<u64>,
+) {
+    if old == new {
+        return;
+    }
+    AUDIT_LOG.with(|l| {
+        let mut log = l.borrow_mut();
+        let seq = log.last_key_value().map_or(0, |(seq, _)| seq + 1);
+        log.insert(
+            seq,
+            Stored(ConfigChange {
+                seq,
+                actor,
+                timestamp,
+                key,
+                old,
+                new,
+                effective_at,
+            }),
+        );
+        while log.len() > MAX_CONFIG_CHANGES {
+            log.pop_first();
+        }
+    });
+}
+
+/// Up to `limit` audit log entries, starting at `from`, or at the oldest kept
+/// entry if `from` has been dropped.
+#[must_use]
+pub fn config_changes(from: u64, limit: usize) -> ConfigChangePage {
+    AUDIT_LOG.with(|l| {
+        let mut changes: Vec<ConfigChange> = l
+            .borrow()
+            .range(from..)
+            .take(limit.saturating_add(1))
+            .map(|entry| entry.value().0)
+            .collect();
+        let next = if changes.len() > limit {
+            changes.pop().map(|change| change.seq)
+        } else {
+            None
+        };
+        ConfigChangePage { changes, next }
+    })
+}
+
+/// Whether `principal` was granted `role`, or the admin role.
+#[must_use]
+pub fn has_role(principal: &Principal, role: Role) -> bool {
+    ROLES.with(|r| {
+        r.borrow()
+            .get(&Key::new(principal))
+            .is_some_and(|roles| roles.0.contains(&role) || roles.0.contains(&Role::Admin))
+    })
+}
+
+/// Grant `role` to `principal`. Returns `false` if it already held it.
+pub fn grant_role(principal: Principal, role: Role) -> bool {
+    ROLES.with(|r| {
+        let mut roles = r.borrow_mut();
+        let key = Key::new(&principal);
+        let mut held = roles.get(&key).map(|held| held.0).unwrap_or_default();
+        let granted = held.insert(role);
+        if granted {
+            roles.insert(key, Stored(held));
+        }
+        granted
+    })
+}
+
+/// Revoke `role` from `principal`. Returns `false` if it did not hold it.
+pub fn revoke_role(principal: &Principal, role: Role) -> bool {
+    ROLES.with(|r| {
+        let mut roles = r.borrow_mut();
+        let key = Key::new(principal);
+        let Some(Stored(mut held)) = roles.get(&key) else {
+            return false;
+        };
+        let revoked = held.remove(&role);
+        if held.is_empty() {
+            roles.remove(&key);
+        } else if revoked {
+            roles.insert(key, Stored(held));
+        }
+        revoked
+    })
+}
+
+/// Snapshot of every principal's granted roles.
+#[must_use]
+pub fn list_roles() -> Vec<(Principal, Vec<Role>)> {
+    ROLES.with(|r| {
+        entries(&r.borrow())
+            .into_iter()
+            .map(|(principal, roles)| (principal, roles.into_iter().collect()))
+            .collect()
+    })
+}
+
+/// Look up the token-to-cycles rate for a ledger.
+#[must_use]
+pub fn get_rate(ledger: &Principal) -> Option<TokenCyclesRate> {
+    RATES.with(|r| r.borrow().get(&Key::new(ledger)).map(|v| v.0))
+}
+
+/// Insert or replace the token-to-cycles rate for a ledger.
+pub fn set_rate(ledger: Principal, rate: TokenCyclesRate) {
+    RATES.with(|r| {
+        r.borrow_mut().insert(Key::new(&ledger), Stored(rate));
+    });
+}
+
+/// Remove the token-to-cycles rate for a ledger, returning any prior value.
+#[must_use]
+pub fn remove_rate(ledger: &Principal) -> Option<TokenCyclesRate> {
+    RATES.with(|r| r.borrow_mut().remove(&Key::new(ledger)).map(|v| v.0))
+}
+
+/// Snapshot of all token-to-cycles rates.
+#[must_use]
+pub fn list_rates() -> Vec<(Principal, TokenCyclesRate)> {
+    RATES.with(|r| entries(&r.borrow()))
+}
+
+/// The fee `ledger` charges for a transfer, if it is known: as recorded by a
+/// pricing manager, or [`CYCLES_LEDGER_FEE`] for the cycles ledger.
+#[must_use]
+pub fn get_ledger_fee(ledger: &Principal) -> Option<u128> {
+    LEDGER_FEES
+        .with(|f| f.borrow().get(&Key::new(ledger)).map(|v| v.0))
+        .or_else(|| (*ledger == cycles_ledger_canister_id()).then_some(CYCLES_LEDGER_FEE))
+}
+
+/// Record the fee `ledger` charges for a transfer.
+pub fn set_ledger_fee(ledger: Principal, fee: u128) {
+    LEDGER_FEES.with(|f| {
+        f.borrow_mut().insert(Key::new(&ledger), Stored(fee));
+    });
+}
+
+/// Forget the recorded fee for `ledger`, returning any prior value.
+#[must_use]
+pub fn remove_ledger_fee(ledger: &Principal) -> Option<u128> {
+    LEDGER_FEES.with(|f| f.borrow_mut().remove(&Key::new(ledger)).map(|v| v.0))
+}
+
+/// Snapshot of all recorded ledger fees.
+#[must_use]
+pub fn list_ledger_fees() -> Vec<(Principal, u128)> {
+    LEDGER_FEES.with(|f| entries(&f.borrow()))
+}
+
+/// The current state of the cycles reserve.
+#[must_use]
+pub fn get_reserve() -> CyclesReserve {
+    settings().reserve
+}
+
+/// Replace the cycles reserve.
+pub fn set_reserve(reserve: CyclesReserve) {
+    update_settings(|s| s.reserve = reserve);
+}
+
+/// Take `amount` cycles out of the reserve to fund a forwarded call.
+///
+/// # Errors
+/// Returns the available balance if the reserve cannot cover `amount`.
+pub fn take_from_reserve(amount: u128) -> Result<(), u128> {
+    let reserve = get_reserve();
+    if reserve.balance < amount {
+        return Err(reserve.balance);
+    }
+    update_settings(|s| {
+        s.reserve.balance -= amount;
+        s.reserve.spent = s.reserve.spent.saturating_add(amount);
+    });
+    Ok(())
+}
+
+/// Put back cycles taken with [`take_from_reserve`] that were not spent.
+pub fn return_to_reserve(amount: u128) {
+    update_settings(|s| {
+        s.reserve.balance = s.reserve.balance.saturating_add(amount);
+        s.reserve.spent = s.reserve.spent.saturating_sub(amount);
+    });
+}
+
+/// The owner who registered `target`, if any.
+#[must_use]
+pub fn get_target_owner(target: &Principal) -> Option<Principal> {
+    TARGET_OWNERS.with(|o| o.borrow().get(&Key::new(target)).map(|v| v.0))
+}
+
+/// Record `owner` as the owner of `target`, returning any prior owner.
+pub fn set_target_owner(target: Principal, owner: Principal) -> Option<Principal> {
+    TARGET_OWNERS.with(|o| {
+        o.borrow_mut()
+            .insert(Key::new(&target), Stored(owner))
+            .map(|v| v.0)
+    })
+}
+
+/// Forget the owner of `target`, returning it. Balances are kept.
+pub fn remove_target_owner(target: &Principal) -> Option<Principal> {
+    TARGET_OWNERS.with(|o| o.borrow_mut().remove(&Key::new(target)).map(|v| v.0))
+}
+
+/// Snapshot of all registered targets and their owners.
+#[must_use]
+pub fn list_target_owners() -> Vec<(Principal, Principal)> {
+    TARGET_OWNERS.with(|o| entries(&o.borrow()))
+}
+
+/// Record that `claimant` asks to own `target`, pending an admin's approval,
+/// returning any claim it replaces.
+pub fn claim_target(target: Principal, claimant: Principal) -> Option<Principal> {
+    PENDING_OWNERS.with(|o| {
+        o.borrow_mut()
+            .insert(Key::new(&target), Stored(claimant))
+            .map(|v| v.0)
+    })
+}
+
+/// Forget the pending claim on `target`, returning the claimant.
+pub fn take_target_claim(target: &Principal) -> Option<Principal> {
+    PENDING_OWNERS.with(|o| o.borrow_mut().remove(&Key::new(target)).map(|v| v.0))
+}
+
+/// Snapshot of all targets awaiting approval, and who claims them.
+#[must_use]
+pub fn list_target_claims() -> Vec<(Principal, Principal)> {
+    PENDING_OWNERS.with(|o| entries(&o.borrow()))
+}
+
+/// The share of owners' revenue the wrapper keeps, in basis points.
+#[must_use]
+pub fn get_platform_fee_bps() -> u32 {
+    settings().platform_fee_bps
+}
+
+/// Set the share of owners' revenue the wrapper keeps, in basis points.
+pub fn set_platform_fee_bps(bps: u32) {
+    update_settings(|s| s.platform_fee_bps = bps);
+}
+
+/// Credit the owner of `target`, if it has one, with `revenue` less the
+/// platform fee. Returns the amount credited.
+pub fn credit_owner(target: &Principal, denom: FeeDenom, revenue: u128) -> Option<u128> {
+    let owner = get_target_owner(target)?;
+    let platform_fee = mul_div(revenue, u128::from(get_platform_fee_bps()), 10_000, false);
+    let share = revenue.saturating_sub(platform_fee);
+    add_to_owner_balance(owner, denom, share);
+    Some(share)
+}
+
+/// Add `amount` to `owner`'s balance in `denom`.
+pub fn add_to_owner_balance(owner: Principal, denom: FeeDenom, amount: u128) {
+    if amount == 0 {
+        return;
+    }
+    OWNER_BALANCES.with(|b| {
+        let mut balances = b.borrow_mut();
+        let key = Key::new(&(owner, denom));
+        let balance = balances.get(&key).map_or(0, |v| v.0);
+        balances.insert(key, Stored(balance.saturating_add(amount)));
+    });
+}
+
+/// Take `amount` out of `owner`'s balance in `denom`.
+///
+/// # Errors
+/// Returns the balance if it cannot cover `amount`.
+pub fn debit_owner(owner: Principal, denom: FeeDenom, amount: u128) -> Result<(), u128> {
+    OWNER_BALANCES.with(|b| {
+        let mut balances = b.borrow_mut();
+        let key = Key::new(&(owner, denom));
+        let balance = balances.get(&key).map_or(0, |v| v.0);
+        if balance < amount {
+            return Err(balance);
+        }
+        if balance == amount {
+            balances.remove(&key);
+        } else {
+            balances.insert(key, Stored(balance - amount));
+        }
+        Ok(())
+    })
+}
+
+/// `owner`'s balances, by denomination.
+#[must_use]
+pub fn owner_balances(owner: &Principal) -> Vec<(FeeDenom, u128)> {
+    OWNER_BALANCES.with(|b| {
+        let mut balances: Vec<_> = entries(&b.borrow())
+            .into_iter()
+            .filter(|((o, _), _)| o == owner)
+            .map(|((_, denom), amount)| (denom, amount))
+            .collect();
+        balances.sort();
+        balances
+    })
+}
+
+/// Snapshot of all owner balances.
+#[must_use]
+pub fn list_owner_balances() -> Vec<(Principal, FeeDenom, u128)> {
+    OWNER_BALANCES.with(|b| {
+        entries(&b.borrow())
+            .into_iter()
+            .map(|((owner, denom), amount)| (owner, denom, amount))
+            .collect()
+    })
+}
+
+/// The entry of [`REQUEST_QUEUE`] for a request: when it was received,
+/// big-endian so that entries sort oldest first, followed by its key.
+fn queue_entry(received_at: u64, key: &Key<RequestKey>) -> Vec<u8> {
+    let mut entry = received_at.to_be_bytes().to_vec();
+    entry.extend_from_slice(key.as_bytes());
+    entry
+}
+
+fn parse_queue_entry(mut entry: Vec<u8>) -> (u64, Key<RequestKey>) {
+    let key = entry.split_off(8);
+    let received_at = u64::from_be_bytes(
+        entry
+            .try_into()
+            .unwrap_or_else(|_| panic!("Malformed request queue entry")),
+    );
+    (received_at, Key::from_vec(key))
+}
+
+/// The prefix of the [`CALLER_REQUESTS`] entries of `caller`.
+fn caller_prefix(caller: &Principal) -> Vec<u8> {
+    let bytes = caller.as_slice();
+    let mut prefix = vec![u8::try_from(bytes.len()).expect("principals are short")];
+    prefix.extend_from_slice(bytes);
+    prefix
+}
+
+/// The entry of [`CALLER_REQUESTS`] for a request: its [`queue_entry`],
+/// prefixed with the caller, so that each caller's requests sort together.
+fn caller_entry(caller: &Principal, queue_entry: &[u8]) -> Vec<u8> {
+    let mut entry = caller_prefix(caller);
+    entry.extend_from_slice(queue_entry);
+    entry
+}
+
+/// The [`queue_entry`]s of the requests of `caller`, oldest first.
+fn caller_requests(caller: &Principal) -> Vec<Vec<u8>> {
+    let prefix = caller_prefix(caller);
+    CALLER_REQUESTS.with(|c| {
         c.borrow()
+            .range(prefix.clone()..)
+            .take_while(|entry| entry.starts_with(&prefix))
+            .map(|entry| entry[prefix.len()..].to_vec())
+            .collect()
+    })
+}
+
+/// Forget a remembered request, given its [`queue_entry`].
+fn forget_request(entry: &[u8]) {
+    let (_, key) = parse_queue_entry(entry.to_vec());
+    REQUESTS.with(|r| r.borrow_mut().remove(&key));
+    REQUEST_QUEUE.with(|q| q.borrow_mut().remove(&entry.to_vec()));
+    COMPLETED_QUEUE.with(|q| q.borrow_mut().remove(&entry.to_vec()));
+    CALLER_REQUESTS.with(|c| c.borrow_mut().remove(&caller_entry(&key.get().0, entry)));
+}
+
+/// Whether the request of a [`queue_entry`] has completed.
+fn is_completed(entry: &[u8]) -> bool {
+    COMPLETED_QUEUE.with(|q| q.borrow().contains(&entry.to_vec()))
+}
+
+/// Forget the requests received `REQUEST_TTL_NANOS` or more before `now`.
+fn forget_expired_requests(now: u64) {
+    while let Some(entry) = REQUEST_QUEUE.with(|q| q.borrow().first()) {
+        let (received_at, _) = parse_queue_entry(entry.clone());
+        if now.saturating_sub(received_at) < REQUEST_TTL_NANOS {
+            break;
+        }
+        forget_request(&entry);
+    }
+}
+
+/// Make room for another request of `caller`, forgetting the oldest completed
+/// request of the caller, or of anyone, if necessary. Requests that are still
+/// in flight are never forgotten early: that would let their retries be
+/// charged again.
+fn make_room_for_request(caller: &Principal) -> Result<(), BridgeError> {
+    let own = caller_requests(caller);
+    if own.len() >= MAX_REQUESTS_PER_CALLER {
+        let oldest =
+            own.iter()
+                .find(|entry| is_completed(entry))
+                .ok_or(BridgeError::TooManyRequests {
+                    max_requests: MAX_REQUESTS_PER_CALLER as u64,
+                })?;
+        forget_request(oldest);
+    }
+    if REQUESTS.with(|r| r.borrow().len()) >= MAX_STORED_REQUESTS as u64 {
+        let oldest =
+            COMPLETED_QUEUE
+                .with(|q| q.borrow().first())
+                .ok_or(BridgeError::TooManyRequests {
+                    max_requests: MAX_STORED_REQUESTS as u64,
+                })?;
+        forget_request(&oldest);
+    }
+    Ok(())
+}
+
+/// Start tracking a call made with a request id.
+///
+/// Returns the stored outcome if the same caller already completed a call with
+/// this id, or `None` if the call should go ahead.
+///
+/// # Errors
+/// - If the request id is too long.
+/// - If a call with this id is still in flight.
+/// - If this id was used for a different target or method, or for a call
+///   with another `fingerprint`.
+/// - If no more requests of the caller, or of anyone, can be remembered
+///   because they are all still in flight.
+pub fn begin_request(
+    key: RequestKey,
+    target: Principal,
+    method: &str,
+    fingerprint: [u8; 32],
+    now: u64,
+) -> Result<Option<Result<BridgeReply, BridgeError>>, BridgeError> {
+    if key.1.len() > MAX_REQUEST_ID_BYTES {
+        return Err(BridgeError::InvalidRequestId {
+            max_bytes: MAX_REQUEST_ID_BYTES as u64,
+        });
+    }
+    forget_expired_requests(now);
+    let caller = key.0;
+    let key = Key::new(&key);
+    if let Some(Stored(request)) = REQUESTS.with(|r| r.borrow().get(&key)) {
+        if request.target != target || request.method != method {
+            return Err(BridgeError::RequestIdReused {
+                target: request.target,
+                method: request.method,
+            });
+        }
+        if request.fingerprint.as_slice() != fingerprint {
+            return Err(BridgeError::RequestIdReusedWithOtherArgs);
+        }
+        return request
+            .outcome
+            .map(Some)
+            .ok_or(BridgeError::RequestInProgress);
+    }
+    make_room_for_request(&caller)?;
+    let entry = queue_entry(now, &key);
+    CALLER_REQUESTS.with(|c| c.borrow_mut().insert(caller_entry(&caller, &entry)));
+    REQUEST_QUEUE.with(|q| q.borrow_mut().insert(entry));
+    REQUESTS.with(|r| {
+        r.borrow_mut().insert(
+            key,
+            Stored(StoredRequest {
+                target,
+                method: method.to_string(),
+                fingerprint: ByteBuf::from(fingerprint),
+                received_at: now,
+                outcome: None,
+            }),
+        )
+    });
+    Ok(None)
+}
+
+/// Record the outcome of a call started with [`begin_request`].
+pub fn complete_request(key: &RequestKey, outcome: Result<BridgeReply, BridgeError>) {
+    REQUESTS.with(|r| {
+        let mut requests = r.borrow_mut();
+        let key = Key::new(key);
+        if let Some(Stored(mut request)) = requests.get(&key) {
+            COMPLETED_QUEUE.with(|q| {
+                q.borrow_mut()
+                    .insert(queue_entry(request.received_at, &key))
+            });
+            request.outcome = Some(outcome);
+            requests.insert(key, Stored(request));
+        }
+    });
+}
+
+/// Forget a call started with [`begin_request`] that was not charged, so that
+/// it may be retried.
+pub fn abandon_request(key: &RequestKey) {
+    let key = Key::new(key);
+    if let Some(Stored(request)) = REQUESTS.with(|r| r.borrow().get(&key)) {
+        forget_request(&queue_entry(request.received_at, &key));
+    }
+}
+
+/// Snapshot of all remembered requests.
+#[must_use]
+pub fn list_requests() -> Vec<(RequestKey, StoredRequest)> {
+    REQUESTS.with(|r| entries(&r.borrow()))
+}
+
+/// Look up an unexpired cached reply.
+#[must_use]
+pub fn get_cached_reply(key: &CacheKey, now: u64) -> Option<Vec<u8>> {
+    CACHE.with(|c| {
+        c.borrow()
+            .get(key)
+            .filter(|cached| now < cached.expires_at)
+            .map(|cached| cached.reply.clone())
+    })
+}
+
+/// Cache a reply until `expires_at`, evicting the entries closest to expiry
+/// if the cache is full. Replies too large for the cache are not stored.
+pub fn put_cached_reply(key: CacheKey, reply: Vec<u8>, now: u64, expires_at: u64) {
+    if reply.len() > MAX_CACHED_BYTES {
+        return;
+    }
+    CACHE.with(|c| {
+        let mut cache = c.borrow_mut();
+        cache.retain(|_, cached| now < cached.expires_at);
+        cache.remove(&key);
+        let mut bytes: usize = cache.values().map(|cached| cached.reply.len()).sum();
+        while cache.len() >= MAX_CACHED_REPLIES || bytes + reply.len() > MAX_CACHED_BYTES {
+            let Some(soonest) = cache
+                .iter()
+                .min_by_key(|(_, cached)| cached.expires_at)
+                .map(|(key, _)| key.clone())
+            else {
+                break;
+            };
+            if let Some(evicted) = cache.remove(&soonest) {
+                bytes -= evicted.reply.len();
+            }
+        }
+        cache.insert(key, CachedReply { reply, expires_at });
+    });
+}
+
+/// Drop every cached reply for a method, e.g. because its configuration changed.
+pub fn clear_cached_replies(method: &MethodKey) {
+    CACHE.with(|c| c.borrow_mut().retain(|(key, _), _| key != method));
+}
+
+/// Forget the cached replies of every method a rule matches.
+pub fn clear_cached_replies_for_rule(rule: &PricingRule) {
+    CACHE.with(|c| c.borrow_mut().retain(|(key, _), _| !rule.matches(key)));
+}
+
+/// Pause all calls to a target. Returns `false` if it was already paused.
+pub fn pause_target(target: Principal) -> bool {
+    PAUSED_TARGETS.with(|p| p.borrow_mut().insert(Key::new(&target)))
+}
+
+/// Resume calls to a target. Returns `false` if it was not paused.
+pub fn resume_target(target: &Principal) -> bool {
+    PAUSED_TARGETS.with(|p| p.borrow_mut().remove(&Key::new(target)))
+}
+
+/// Pause calls to a single method. Returns `false` if it was already paused.
+pub fn pause_method(key: MethodKey) -> bool {
+    PAUSED_METHODS.with(|p| p.borrow_mut().insert(Key::new(&key)))
+}
+
+/// Resume calls to a single method. Returns `false` if it was not paused.
+pub fn resume_method(key: &MethodKey) -> bool {
+    PAUSED_METHODS.with(|p| p.borrow_mut().remove(&Key::new(key)))
+}
+
+/// Fails if the method, or its whole target, is paused.
+///
+/// # Errors
+/// [`BridgeError::TargetPaused`] or [`BridgeError::MethodPaused`].
+pub fn check_not_paused(key: &MethodKey) -> Result<(), BridgeError> {
+    if PAUSED_TARGETS.with(|p| p.borrow().contains(&Key::new(&key.target))) {
+        return Err(BridgeError::TargetPaused { target: key.target });
+    }
+    if PAUSED_METHODS.with(|p| p.borrow().contains(&Key::new(key))) {
+        return Err(BridgeError::MethodPaused {
+            target: key.target,
+            method: key.method.clone(),
+        });
+    }
+    Ok(())
+}
+
+/// Snapshot of the paused targets and methods.
+#[must_use]
+pub fn get_pauses() -> Pauses {
+    Pauses {
+        targets: PAUSED_TARGETS.with(|p| p.borrow().iter().map(|target| target.get()).collect()),
+        methods: PAUSED_METHODS.with(|p| p.borrow().iter().map(|key| key.get()).collect()),
+    }
+}
+
+/// The circuit breaker configuration, if the breaker is enabled.
+#[must_use]
+pub fn get_breaker_config() -> Option<CircuitBreakerConfig> {
+    settings().breaker
+}
+
+/// Enable, change or (with `None`) disable the circuit breaker. Resets every
+/// target's breaker.
+pub fn set_breaker_config(config: Option<CircuitBreakerConfig>) {
+    update_settings(|s| s.breaker = config);
+    BREAKERS.with(|b| b.borrow_mut().clear());
+}
+
+/// Fails if the target's circuit breaker is open at `now`.
+///
+/// # Errors
+/// [`BridgeError::CircuitOpen`] until the breaker's cooldown has passed.
+pub fn check_circuit(target: Principal, now: u64) -> Result<(), BridgeError> {
+    BREAKERS.with(|b| {
+        let mut breakers = b.borrow_mut();
+        match breakers.get(&target).and_then(|breaker| breaker.open_until) {
+            Some(open_until) if open_until > now => {
+                Err(BridgeError::CircuitOpen { target, open_until })
+            }
+            // The cooldown has passed: start afresh.
+            Some(_) => {
+                breakers.remove(&target);
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    })
+}
+
+/// Record that a forwarded call to `target` succeeded, which resets its breaker.
+pub fn record_success(target: &Principal) {
+    BREAKERS.with(|b| b.borrow_mut().remove(target));
+}
+
+/// Record that a forwarded call to `target` failed at `now`, tripping its
+/// breaker if this completes a run of failures.
+pub fn record_failure(target: Principal, now: u64) {
+    let Some(config) = get_breaker_config() else {
+        return;
+    };
+    let window = config.window_seconds.saturating_mul(1_000_000_000);
+    BREAKERS.with(|b| {
+        let mut breakers = b.borrow_mut();
+        let breaker = breakers.entry(target).or_insert(Breaker {
+            consecutive_failures: 0,
+            first_failure_at: now,
+            open_until: None,
+        });
+        if breaker.open_until.is_some() {
+            // Calls that were already under way when the breaker tripped.
+            return;
+        }
+        if breaker.consecutive_failures == 0
+            || now.saturating_sub(breaker.first_failure_at) > window
+        {
+            breaker.consecutive_failures = 0;
+            breaker.first_failure_at = now;
+        }
+        breaker.consecutive_failures = breaker.consecutive_failures.saturating_add(1);
+        if breaker.consecutive_failures >= config.failure_threshold {
+            breaker.open_until =
+                Some(now.saturating_add(config.cooldown_seconds.saturating_mul(1_000_000_000)));
+        }
+    });
+}
+
+/// Close a target's circuit breaker and forget its failures.
+pub fn reset_breaker(target: &Principal) {
+    BREAKERS.with(|b| b.borrow_mut().remove(target));
+}
+
+/// Snapshot of the targets whose breakers have counted failures or are open.
+#[must_use]
+pub fn list_breakers() -> Vec<CircuitBreakerStatus> {
+    BREAKERS.with(|b| {
+        b.borrow()
             .iter()
-            .map(|(k, v)| (k.clone(), v.clone()))
+            .map(|(target, breaker)| CircuitBreakerStatus {
+                target: *target,
+                consecutive_failures: breaker.consecutive_failures,
+                open_until: breaker.open_until,
+            })
             .collect()
     })
 }
 
-/// Replace the whole registry (used when restoring after an upgrade).
-pub fn replace_all(items: Vec<(MethodKey, MethodConfig)>) {
-    CONFIGS.with(|c| {
-        let mut map = c.borrow_mut();
-        map.clear();
-        for (k, v) in items {
-            map.insert(k, v);
+/// Approve `hash` as the module of `target`, clearing any recorded change.
+/// Returns the prior pin.
+pub fn pin_module(target: Principal, hash: Vec<u8>) -> Option<ModulePin> {
+    MODULE_PINS.with(|p| {
+        p.borrow_mut()
+            .insert(
+                Key::new(&target),
+                Stored(ModulePin {
+                    hash: hash.into(),
+                    changed: None,
+                }),
+            )
+            .map(|v| v.0)
+    })
+}
+
+/// Stop checking the code of `target`, returning its pin.
+pub fn unpin_module(target: &Principal) -> Option<ModulePin> {
+    MODULE_PINS.with(|p| p.borrow_mut().remove(&Key::new(target)).map(|v| v.0))
+}
+
+/// The pin of `target`, if its code is checked.
+#[must_use]
+pub fn get_module_pin(target: &Principal) -> Option<ModulePin> {
+    MODULE_PINS.with(|p| p.borrow().get(&Key::new(target)).map(|v| v.0))
+}
+
+/// Snapshot of all module pins.
+#[must_use]
+pub fn list_module_pins() -> Vec<(Principal, ModulePin)> {
+    MODULE_PINS.with(|p| entries(&p.borrow()))
+}
+
+/// Refuse calls to a pinned target whose code is known to have changed.
+///
+/// # Errors
+/// [`BridgeError::ModuleHashChanged`] if a change has been recorded.
+pub fn check_module_pin(target: Principal) -> Result<(), BridgeError> {
+    match get_module_pin(&target) {
+        Some(ModulePin {
+            hash,
+            changed: Some(change),
+        }) => Err(BridgeError::ModuleHashChanged {
+            target,
+            expected: hash,
+            actual: change.observed,
+        }),
+        _ => Ok(()),
+    }
+}
+
+/// Compare the module `observed` on `target` with its pin, recording a change
+/// if they differ.
+///
+/// # Errors
+/// [`BridgeError::ModuleHashChanged`] if the target runs other code, now or
+/// when last checked.
+pub fn record_module_hash(
+    target: Principal,
+    observed: Option<Vec<u8>>,
+    now: u64,
+) -> Result<(), BridgeError> {
+    if let Some(mut pin) = get_module_pin(&target) {
+        if pin.changed.is_none() && observed.as_deref() == Some(pin.hash.as_slice()) {
+            MODULE_CHECKS.with(|c| c.borrow_mut().insert(target, (pin.hash.into_vec(), now)));
+        } else if pin.changed.is_none() {
+            pin.changed = Some(ModuleChange {
+                observed: observed.map(ByteBuf::from),
+                detected_at: now,
+            });
+            MODULE_PINS.with(|p| p.borrow_mut().insert(Key::new(&target), Stored(pin)));
         }
+    }
+    check_module_pin(target)
+}
+
+/// Whether `target` was found to run its pinned module less than
+/// [`MODULE_CHECK_TTL_NANOS`] before `now`, and no change has been recorded
+/// since.
+#[must_use]
+pub fn module_recently_verified(target: &Principal, now: u64) -> bool {
+    let Some(pin) = get_module_pin(target).filter(|pin| pin.changed.is_none()) else {
+        return false;
+    };
+    MODULE_CHECKS.with(|c| {
+        c.borrow().get(target).is_some_and(|(hash, checked_at)| {
+            *hash == pin.hash.as_slice() && now < checked_at.saturating_add(MODULE_CHECK_TTL_NANOS)
+        })
+    })
+}
+
+/// The entry of [`PREFERENCE_QUEUE`] for the preference of `caller`, last
+/// used at `used_at`: the time in big-endian order, so that entries sort by
+/// it, followed by the caller.
+fn preference_entry(caller: &Principal, used_at: u64) -> Vec<u8> {
+    let mut entry = used_at.to_be_bytes().to_vec();
+    entry.extend_from_slice(caller.as_slice());
+    entry
+}
+
+/// Record that the preference of `caller` was set or used at `now`.
+fn touch_payment_preference(caller: Principal, now: u64) {
+    let last_used = PREFERENCE_USES.with(|u| {
+        u.borrow_mut()
+            .insert(Key::new(&caller), Stored(now))
+            .map(|v| v.0)
+    });
+    PREFERENCE_QUEUE.with(|q| {
+        let mut queue = q.borrow_mut();
+        if let Some(last_used) = last_used {
+            queue.remove(&preference_entry(&caller, last_used));
+        }
+        queue.insert(preference_entry(&caller, now));
     });
 }
+
+/// When the preference of `caller` was last set or used.
+fn preference_last_used(caller: &Principal, now: u64) -> u64 {
+    PREFERENCE_USES.with(|u| u.borrow().get(&Key::new(caller)).map_or(now, |v| v.0))
+}
+
+/// Whether a preference last used at `used_at` has expired at `now`.
+fn preference_expired(used_at: u64, now: u64) -> bool {
+    now >= used_at.saturating_add(PAYMENT_PREFERENCE_TTL_NANOS)
+}
+
+/// The payment type `caller` uses when it does not name one, if it chose one
+/// and it has not expired at `now`.
+#[must_use]
+pub fn get_payment_preference(caller: &Principal, now: u64) -> Option<PaymentType> {
+    PAYMENT_PREFERENCES
+        .with(|p| p.borrow().get(&Key::new(caller)).map(|v| v.0))
+        .filter(|_| !preference_expired(preference_last_used(caller, now), now))
+}
+
+/// Like [`get_payment_preference`], and records that the preference was used
+/// at `now`, which keeps it from expiring. An expired preference is
+/// forgotten.
+pub fn use_payment_preference(caller: &Principal, now: u64) -> Option<PaymentType> {
+    let payment = PAYMENT_PREFERENCES.with(|p| p.borrow().get(&Key::new(caller)).map(|v| v.0))?;
+    let last_used = preference_last_used(caller, now);
+    if preference_expired(last_used, now) {
+        let _ = remove_payment_preference(caller);
+        return None;
+    }
+    if now >= last_used.saturating_add(PAYMENT_PREFERENCE_USE_INTERVAL_NANOS)
+        || PREFERENCE_USES.with(|u| !u.borrow().contains_key(&Key::new(caller)))
+    {
+        touch_payment_preference(*caller, now);
+    }
+    Some(payment)
+}
+
+/// Record the payment type `caller` uses when it does not name one, returning
+/// any prior preference that had not expired.
+///
+/// When the wrapper keeps as many preferences as it may, the least recently
+/// used one is forgotten to make room, if it has expired.
+///
+/// # Errors
+/// Returns [`MAX_PAYMENT_PREFERENCES`] if that many callers already have a
+/// preference that has not expired and `caller` is not among them.
+pub fn set_payment_preference(
+    caller: Principal,
+    payment: PaymentType,
+    now: u64,
+) -> Result<Option<PaymentType>, u64> {
+    let prior = get_payment_preference(&caller, now);
+    let key = Key::new(&caller);
+    let full = PAYMENT_PREFERENCES
+        .with(|p| p.borrow().len() >= MAX_PAYMENT_PREFERENCES && !p.borrow().contains_key(&key));
+    if full {
+        let oldest = PREFERENCE_QUEUE.with(|q| q.borrow().first());
+        let expired = oldest.as_ref().and_then(|entry| {
+            let (used_at, principal) = entry.split_at(8);
+            let used_at = u64::from_be_bytes(used_at.try_into().expect("an 8-byte timestamp"));
+            preference_expired(used_at, now).then(|| Principal::from_slice(principal))
+        });
+        let Some(expired) = expired else {
+            return Err(MAX_PAYMENT_PREFERENCES);
+        };
+        let _ = remove_payment_preference(&expired);
+    }
+    PAYMENT_PREFERENCES.with(|p| p.borrow_mut().insert(key, Stored(payment)));
+    touch_payment_preference(caller, now);
+    Ok(prior)
+}
+
+/// Forget the payment preference of `caller`, returning it.
+pub fn remove_payment_preference(caller: &Principal) -> Option<PaymentType> {
+    if let Some(used_at) = PREFERENCE_USES.with(|u| u.borrow_mut().remove(&Key::new(caller))) {
+        PREFERENCE_QUEUE.with(|q| q.borrow_mut().remove(&preference_entry(caller, used_at.0)));
+    }
+    PAYMENT_PREFERENCES.with(|p| p.borrow_mut().remove(&Key::new(caller)).map(|v| v.0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(id: &str) -> RequestKey {
+        (Principal::anonymous(), id.to_string())
+    }
+
+    fn reply() -> Result<BridgeReply, BridgeError> {
+        Ok(BridgeReply {
+            reply: vec![1, 2, 3].into(),
+            cycles_forwarded: 0,
+            cycles_unused: 0,
+            cycles_unused_error: None,
+            reply_reserve_returned: 0,
+            reply_reserve_error: None,
+            from_cache: false,
+        })
+    }
+
+    #[test]
+    fn completed_request_is_replayed() {
+        let target = Principal::anonymous();
+        assert_eq!(begin_request(key("a"), target, "m", [0; 32], 0), Ok(None));
+        assert_eq!(
+            begin_request(key("a"), target, "m", [0; 32], 1),
+            Err(BridgeError::RequestInProgress)
+        );
+        complete_request(&key("a"), reply());
+        assert_eq!(
+            begin_request(key("a"), target, "m", [0; 32], 2),
+            Ok(Some(reply()))
+        );
+    }
+
+    #[test]
+    fn abandoned_request_may_be_retried() {
+        let target = Principal::anonymous();
+        assert_eq!(begin_request(key("a"), target, "m", [0; 32], 0), Ok(None));
+        abandon_request(&key("a"));
+        assert_eq!(begin_request(key("a"), target, "m", [0; 32], 1), Ok(None));
+    }
+
+    #[test]
+    fn request_id_is_bound_to_the_call() {
+        let target = Principal::anonymous();
+        assert_eq!(begin_request(key("a"), target, "m", [0; 32], 0), Ok(None));
+        assert!(matches!(
+            begin_request(key("a"), target, "other", [0; 32], 1),
+            Err(BridgeError::RequestIdReused { .. })
+        ));
+        complete_request(&key("a"), reply());
+        assert_eq!(
+            begin_request(key("a"), target, "m", [1; 32], 2),
+            Err(BridgeError::RequestIdReusedWithOtherArgs)
+        );
+    }
+
+    #[test]
+    fn cycles_ledger_fee_is_known_unless_overridden() {
+        let cycles_ledger = cycles_ledger_canister_id();
+        let token_ledger = Principal::from_slice(&[1]);
+        assert_eq!(get_ledger_fee(&cycles_ledger), Some(CYCLES_LEDGER_FEE));
+        assert_eq!(get_ledger_fee(&token_ledger), None);
+        set_ledger_fee(token_ledger, 10_000);
+        set_ledger_fee(cycles_ledger, 1);
+        assert_eq!(get_ledger_fee(&token_ledger), Some(10_000));
+        assert_eq!(get_ledger_fee(&cycles_ledger), Some(1));
+        assert_eq!(remove_ledger_fee(&cycles_ledger), Some(1));
+        assert_eq!(get_ledger_fee(&cycles_ledger), Some(CYCLES_LEDGER_FEE));
+    }
+
+    #[test]
+    fn target_claims_wait_for_approval() {
+        let target = Principal::from_slice(&[3]);
+        let (first, second) = (Principal::from_slice(&[4]), Principal::from_slice(&[5]));
+        assert_eq!(claim_target(target, first), None);
+        assert_eq!(claim_target(target, second), Some(first));
+        assert_eq!(get_target_owner(&target), None);
+        assert_eq!(list_target_claims(), vec![(target, second)]);
+        assert_eq!(take_target_claim(&target), Some(second));
+        assert!(list_target_claims().is_empty());
+        assert_eq!(get_platform_fee_bps(), DEFAULT_PLATFORM_FEE_BPS);
+    }
+
+    #[test]
+    fn owners_earn_revenue_less_the_platform_fee() {
+        let target = Principal::from_slice(&[1]);
+        let owner = Principal::from_slice(&[2]);
+        assert_eq!(credit_owner(&target, FeeDenom::Cycles, 1_000), None);
+        set_target_owner(target, owner);
+        set_platform_fee_bps(2_500);
+        assert_eq!(credit_owner(&target, FeeDenom::Cycles, 1_000), Some(750));
+        assert_eq!(credit_owner(&target, FeeDenom::Cycles, 3), Some(3));
+        assert_eq!(owner_balances(&owner), vec![(FeeDenom::Cycles, 753)]);
+        assert_eq!(debit_owner(owner, FeeDenom::Cycles, 754), Err(753));
+        assert_eq!(debit_owner(owner, FeeDenom::Cycles, 753), Ok(()));
+        assert_eq!(owner_balances(&owner), vec![]);
+    }
+
+    #[test]
+    fn module_change_is_latched_until_repinned() {
+        let target = Principal::from_slice(&[1]);
+        let (old, new) = (vec![1; 32], vec![2; 32]);
+        assert_eq!(record_module_hash(target, Some(new.clone()), 0), Ok(()));
+        pin_module(target, old.clone());
+        assert_eq!(record_module_hash(target, Some(old.clone()), 1), Ok(()));
+        assert!(matches!(
+            record_module_hash(target, Some(new.clone()), 2),
+            Err(BridgeError::ModuleHashChanged { .. })
+        ));
+        // Reverting the code does not lift the refusal; the operator must.
+        assert!(record_module_hash(target, Some(old), 3).is_err());
+        assert!(check_module_pin(target).is_err());
+        assert_eq!(
+            get_module_pin(&target).and_then(|pin| pin.changed),
+            Some(ModuleChange {
+                observed: Some(new.clone().into()),
+                detected_at: 2,
+            })
+        );
+        pin_module(target, new.clone());
+        assert_eq!(record_module_hash(target, Some(new), 4), Ok(()));
+        assert!(matches!(
+            record_module_hash(target, None, 5),
+            Err(BridgeError::ModuleHashChanged { actual: None, .. })
+        ));
+    }
+
+    #[test]
+    fn matching_module_hash_is_trusted_briefly() {
+        let target = Principal::from_slice(&[1]);
+        let (old, new) = (vec![1; 32], vec![2; 32]);
+        pin_module(target, old.clone());
+        assert!(!module_recently_verified(&target, 0));
+        assert_eq!(record_module_hash(target, Some(old.clone()), 10), Ok(()));
+        assert!(module_recently_verified(&target, 10));
+        assert!(!module_recently_verified(
+            &target,
+            10 + MODULE_CHECK_TTL_NANOS
+        ));
+        // Approving other code invalidates the check at once.
+        pin_module(target, new);
+        assert!(!module_recently_verified(&target, 11));
+        pin_module(target, old);
+        assert!(module_recently_verified(&target, 11));
+        unpin_module(&target);
+        assert!(!module_recently_verified(&target, 11));
+    }
+
+    #[test]
+    fn payment_preference_is_kept_per_caller() {
+        let (alice, bob) = (Principal::from_slice(&[1]), Principal::from_slice(&[2]));
+        let tokens =
+            PaymentType::CallerPaysIcrc2Tokens(ic_papi_api::caller::CallerPaysIcrc2Tokens {
+                ledger: Principal::from_slice(&[3]),
+            });
+        assert_eq!(set_payment_preference(alice, tokens.clone(), 0), Ok(None));
+        assert_eq!(get_payment_preference(&alice, 0), Some(tokens.clone()));
+        assert_eq!(get_payment_preference(&bob, 0), None);
+        assert_eq!(
+            set_payment_preference(alice, PaymentType::CallerPaysIcrc2Cycles, 0),
+            Ok(Some(tokens))
+        );
+        assert_eq!(
+            remove_payment_preference(&alice),
+            Some(PaymentType::CallerPaysIcrc2Cycles)
+        );
+        assert_eq!(get_payment_preference(&alice, 0), None);
+    }
+
+    #[test]
+    fn unused_payment_preferences_expire() {
+        let (alice, bob) = (Principal::from_slice(&[1]), Principal::from_slice(&[2]));
+        let day = PAYMENT_PREFERENCE_USE_INTERVAL_NANOS;
+        let ttl = PAYMENT_PREFERENCE_TTL_NANOS;
+        assert_eq!(
+            set_payment_preference(alice, PaymentType::CallerPaysIcrc2Cycles, 0),
+            Ok(None)
+        );
+        assert_eq!(
+            set_payment_preference(bob, PaymentType::CallerPaysIcrc2Cycles, 0),
+            Ok(None)
+        );
+        // Using a preference keeps it alive.
+        assert!(use_payment_preference(&alice, day).is_some());
+        assert!(use_payment_preference(&alice, ttl).is_some());
+        assert_eq!(get_payment_preference(&bob, ttl), None);
+        assert_eq!(use_payment_preference(&bob, ttl), None);
+        assert_eq!(PREFERENCE_QUEUE.with(|q| q.borrow().len()), 1);
+        assert!(get_payment_preference(&alice, 2 * ttl - 1).is_some());
+        assert_eq!(get_payment_preference(&alice, 2 * ttl), None);
+    }
+
+    #[test]
+    fn requests_expire() {
+        let target = Principal::anonymous();
+        assert_eq!(begin_request(key("a"), target, "m", [0; 32], 0), Ok(None));
+        complete_request(&key("a"), reply());
+        assert_eq!(
+            begin_request(key("a"), target, "m", [0; 32], REQUEST_TTL_NANOS),
+            Ok(None)
+        );
+    }
+
+    #[test]
+    fn oldest_completed_request_is_evicted_when_full() {
+        let target = Principal::anonymous();
+        for i in 0..MAX_STORED_REQUESTS {
+            let now = u64::try_from(i).unwrap();
+            let caller = Principal::from_slice(&(i / MAX_REQUESTS_PER_CALLER).to_be_bytes());
+            let key = (caller, i.to_string());
+            assert_eq!(
+                begin_request(key.clone(), target, "m", [0; 32], now),
+                Ok(None)
+            );
+            if i == 1 || i == 2 {
+                complete_request(&key, reply());
+            }
+        }
+        let alice = Principal::from_slice(&[0xaa]);
+        assert_eq!(
+            begin_request((alice, "new".to_string()), target, "m", [0; 32], 1),
+            Ok(None)
+        );
+        assert_eq!(list_requests().len(), MAX_STORED_REQUESTS);
+        let first = Principal::from_slice(&0_usize.to_be_bytes());
+        // The oldest request is still in flight, so the oldest completed one
+        // was forgotten instead, and the next one kept.
+        assert_eq!(
+            begin_request((first, "0".to_string()), target, "m", [0; 32], 2),
+            Err(BridgeError::RequestInProgress)
+        );
+        assert_eq!(
+            begin_request((first, "2".to_string()), target, "m", [0; 32], 2),
+            Ok(Some(reply()))
+        );
+        assert_eq!(
+            begin_request((first, "1".to_string()), target, "m", [0; 32], 2),
+            Ok(None)
+        );
+    }
+
+    #[test]
+    fn requests_are_capped_per_caller() {
+        let target = Principal::anonymous();
+        let alice = Principal::from_slice(&[0xaa]);
+        let bob = Principal::from_slice(&[0xbb]);
+        assert_eq!(
+            begin_request((bob, "b".to_string()), target, "m", [0; 32], 0),
+            Ok(None)
+        );
+        for i in 0..MAX_REQUESTS_PER_CALLER {
+            let now = u64::try_from(i).unwrap();
+            let key = (alice, i.to_string());
+            assert_eq!(
+                begin_request(key.clone(), target, "m", [0; 32], now),
+                Ok(None)
+            );
+        }
+        // All of Alice's requests are in flight, so none may be forgotten.
+        assert_eq!(
+            begin_request((alice, "new".to_string()), target, "m", [0; 32], 1),
+            Err(BridgeError::TooManyRequests {
+                max_requests: MAX_REQUESTS_PER_CALLER as u64
+            })
+        );
+        complete_request(&(alice, "1".to_string()), reply());
+        assert_eq!(
+            begin_request((alice, "new".to_string()), target, "m", [0; 32], 1),
+            Ok(None)
+        );
+        // Alice's oldest completed request was forgotten to make room.
+        let forgotten = (alice, "1".to_string());
+        assert!(!list_requests().iter().any(|(key, _)| *key == forgotten));
+        // Other callers' requests are untouched.
+        assert_eq!(
+            begin_request((bob, "b".to_string()), target, "m", [0; 32], 2),
+            Err(BridgeError::RequestInProgress)
+        );
+    }
+
+    #[test]
+    fn long_request_id_is_rejected() {
+        let id = "x".repeat(MAX_REQUEST_ID_BYTES + 1);
+        assert!(matches!(
+            begin_request(key(&id), Principal::anonymous(), "m", [0; 32], 0),
+            Err(BridgeError::InvalidRequestId { .. })
+        ));
+    }
+
+    fn cache_key(method: &str, args: u8) -> CacheKey {
+        (
+            MethodKey {
+                target: Principal::anonymous(),
+                method: method.to_string(),
+            },
+            [args; 32],
+        )
+    }
+
+    #[test]
+    fn cached_reply_expires() {
+        put_cached_reply(cache_key("m", 1), vec![1], 0, 10);
+        assert_eq!(get_cached_reply(&cache_key("m", 1), 9), Some(vec![1]));
+        assert_eq!(get_cached_reply(&cache_key("m", 1), 10), None);
+        // Different arguments are a different entry.
+        assert_eq!(get_cached_reply(&cache_key("m", 2), 0), None);
+    }
+
+    #[test]
+    fn cache_evicts_soonest_expiring_when_full() {
+        for i in 0..MAX_CACHED_REPLIES {
+            let expires_at = 100 + u64::try_from(i).unwrap();
+            put_cached_reply(cache_key(&i.to_string(), 0), vec![], 0, expires_at);
+        }
+        put_cached_reply(cache_key("new", 0), vec![], 0, 1_000);
+        assert_eq!(get_cached_reply(&cache_key("0", 0), 0), None);
+        assert_eq!(get_cached_reply(&cache_key("1", 0), 0), Some(vec![]));
+        assert_eq!(get_cached_reply(&cache_key("new", 0), 0), Some(vec![]));
+    }
+
+    #[test]
+    fn cache_is_cleared_per_method() {
+        put_cached_reply(cache_key("a", 1), vec![1], 0, 10);
+        put_cached_reply(cache_key("b", 1), vec![2], 0, 10);
+        clear_cached_replies(&cache_key("a", 1).0);
+        assert_eq!(get_cached_reply(&cache_key("a", 1), 0), None);
+        assert_eq!(get_cached_reply(&cache_key("b", 1), 0), Some(vec![2]));
+    }
+
+    #[test]
+    fn paused_target_and_method_are_refused() {
+        let method = MethodKey {
+            target: Principal::anonymous(),
+            method: "m".to_string(),
+        };
+        assert_eq!(check_not_paused(&method), Ok(()));
+        assert!(pause_method(method.clone()));
+        assert!(matches!(
+            check_not_paused(&method),
+            Err(BridgeError::MethodPaused { .. })
+        ));
+        assert!(pause_target(method.target));
+        assert!(matches!(
+            check_not_paused(&method),
+            Err(BridgeError::TargetPaused { .. })
+        ));
+        assert!(resume_target(&method.target));
+        assert!(resume_method(&method));
+        assert!(!resume_method(&method));
+        assert_eq!(check_not_paused(&method), Ok(()));
+    }
+
+    const SECOND: u64 = 1_000_000_000;
+
+    fn enable_breaker() {
+        set_breaker_config(Some(CircuitBreakerConfig {
+            failure_threshold: 3,
+            window_seconds: 60,
+            cooldown_seconds: 300,
+        }));
+    }
+
+    #[test]
+    fn breaker_trips_after_consecutive_failures_and_resets_after_cooldown() {
+        enable_breaker();
+        let target = Principal::anonymous();
+        record_failure(target, 0);
+        record_failure(target, 10 * SECOND);
+        assert_eq!(check_circuit(target, 20 * SECOND), Ok(()));
+        record_failure(target, 20 * SECOND);
+        assert_eq!(
+            check_circuit(target, 21 * SECOND),
+            Err(BridgeError::CircuitOpen {
+                target,
+                open_until: 320 * SECOND
+            })
+        );
+        assert_eq!(check_circuit(target, 320 * SECOND), Ok(()));
+        assert!(list_breakers().is_empty());
+    }
+
+    #[test]
+    fn breaker_counts_only_consecutive_failures_within_the_window() {
+        enable_breaker();
+        let target = Principal::anonymous();
+        record_failure(target, 0);
+        record_failure(target, SECOND);
+        record_success(&target);
+        record_failure(target, 2 * SECOND);
+        record_failure(target, 3 * SECOND);
+        assert_eq!(check_circuit(target, 4 * SECOND), Ok(()));
+        // The window of the run that began at 2s has passed: start a new run.
+        record_failure(target, 70 * SECOND);
+        assert_eq!(check_circuit(target, 71 * SECOND), Ok(()));
+        assert_eq!(list_breakers()[0].consecutive_failures, 1);
+    }
+
+    #[test]
+    fn breaker_is_inert_when_disabled() {
+        set_breaker_config(None);
+        let target = Principal::anonymous();
+        for i in 0..10 {
+            record_failure(target, i);
+        }
+        assert_eq!(check_circuit(target, 10), Ok(()));
+    }
+
+    #[test]
+    fn exact_price_beats_longest_matching_rule() {
+        let target = Principal::anonymous();
+        let key = |method: &str| MethodKey {
+            target,
+            method: method.to_string(),
+        };
+        let rule = |method_prefix: &str| PricingRule {
+            target,
+            method_prefix: method_prefix.to_string(),
+        };
+        assert_eq!(resolve_config(&key("get_balance")), None);
+        set_rule(rule(""), MethodConfig::cycles(1));
+        set_rule(rule("get_"), MethodConfig::cycles(2));
+        set_rule(rule("get_bal"), MethodConfig::cycles(3));
+        assert_eq!(
+            resolve_config(&key("transfer")),
+            Some(MethodConfig::cycles(1))
+        );
+        assert_eq!(
+            resolve_config(&key("get_owner")),
+            Some(MethodConfig::cycles(2))
+        );
+        assert_eq!(
+            resolve_config(&key("get_balance")),
+            Some(MethodConfig::cycles(3))
+        );
+        set_config(key("get_balance"), MethodConfig::cycles(4));
+        assert_eq!(
+            resolve_config(&key("get_balance")),
+            Some(MethodConfig::cycles(4))
+        );
+        assert_eq!(remove_rule(&rule("")), Some(MethodConfig::cycles(1)));
+        assert_eq!(resolve_config(&key("transfer")), None);
+    }
+
+    #[test]
+    fn admin_holds_every_role() {
+        let alice = Principal::anonymous();
+        let bob = Principal::management_canister();
+        assert!(grant_role(alice, Role::Operator));
+        assert!(!grant_role(alice, Role::Operator));
+        assert!(has_role(&alice, Role::Operator));
+        assert!(!has_role(&alice, Role::PricingManager));
+        assert!(grant_role(bob, Role::Admin));
+        for role in [Role::PricingManager, Role::Operator, Role::Auditor] {
+            assert!(has_role(&bob, role));
+        }
+        assert!(revoke_role(&alice, Role::Operator));
+        assert!(!revoke_role(&alice, Role::Operator));
+        assert!(!has_role(&alice, Role::Operator));
+        assert_eq!(list_roles(), vec![(bob, vec![Role::Admin])]);
+    }
+
+    #[test]
+    fn audit_log_is_paginated_and_skips_no_op_changes() {
+        let actor = Principal::anonymous();
+        let key = |method: &str| {
+            ConfigKey::Method(MethodKey {
+                target: actor,
+                method: method.to_string(),
+            })
+        };
+        record_config_change(
+            actor,
+            1,
+            key("a"),
+            None,
+            Some(MethodConfig::cycles(1)),
+            None,
+        );
+        record_config_change(
+            actor,
+            2,
+            key("a"),
+            Some(MethodConfig::cycles(1)),
+            Some(MethodConfig::cycles(1)),
+            None,
+        );
+        record_config_change(
+            actor,
+            3,
+            key("a"),
+            Some(MethodConfig::cycles(1)),
+            Some(MethodConfig::cycles(2)),
+            None,
+        );
+        record_config_change(
+            actor,
+            4,
+            key("b"),
+            None,
+            Some(MethodConfig::cycles(3)),
+            None,
+        );
+        record_config_change(
+            actor,
+            5,
+            key("a"),
+            Some(MethodConfig::cycles(2)),
+            None,
+            None,
+        );
+
+        let page = config_changes(0, 3);
+        assert_eq!(page.changes.len(), 3);
+        assert_eq!(page.next, Some(3));
+        assert_eq!(page.changes[1].seq, 1);
+        assert_eq!(page.changes[1].timestamp, 3);
+        assert_eq!(page.changes[1].old, Some(MethodConfig::cycles(1)));
+        assert_eq!(page.changes[1].new, Some(MethodConfig::cycles(2)));
+        let page = config_changes(3, 3);
+        assert_eq!(page.changes.len(), 1);
+        assert_eq!(page.changes[0].new, None);
+        assert_eq!(page.next, None);
+        assert!(config_changes(10, 3).changes.is_empty());
+    }
+
+    #[test]
+    fn audit_log_drops_the_oldest_entries() {
+        let actor = Principal::anonymous();
+        let key = ConfigKey::Method(MethodKey {
+            target: actor,
+            method: "m".to_string(),
+        });
+        for i in 0..=MAX_CONFIG_CHANGES {
+            let fee = u128::from(i);
+            let (old, new) = (MethodConfig::cycles(fee), MethodConfig::cycles(fee + 1));
+            record_config_change(actor, i, key.clone(), Some(old), Some(new), None);
+        }
+        let page = config_changes(0, 2);
+        assert_eq!(
+            page.changes.iter().map(|c| c.seq).collect::<Vec<_>>(),
+            vec![1, 2]
+        );
+        assert_eq!(page.next, Some(3));
+        let last = config_changes(MAX_CONFIG_CHANGES, 2);
+        assert_eq!(last.changes.len(), 1);
+        assert_eq!(last.changes[0].seq, MAX_CONFIG_CHANGES);
+        assert_eq!(last.next, None);
+    }
+
+    #[test]
+    fn scheduled_config_applies_when_due() {
+        let key = MethodKey {
+            target: Principal::anonymous(),
+            method: "m".to_string(),
+        };
+        set_config(key.clone(), MethodConfig::cycles(1));
+        schedule_config(
+            key.clone(),
+            PendingConfig {
+                config: Some(MethodConfig::cycles(2)),
+                effective_at: 100,
+            },
+        );
+        apply_due_configs(99);
+        assert_eq!(get_config(&key), Some(MethodConfig::cycles(1)));
+        assert!(get_pending_config(&key).is_some());
+        apply_due_configs(100);
+        assert_eq!(get_config(&key), Some(MethodConfig::cycles(2)));
+        assert_eq!(get_pending_config(&key), None);
+        schedule_config(
+            key.clone(),
+            PendingConfig {
+                config: None,
+                effective_at: 200,
+            },
+        );
+        apply_due_configs(200);
+        assert_eq!(get_config(&key), None);
+    }
+
+    #[test]
+    fn envelope_settings_cover_every_price_on_the_target() {
+        let target = Principal::from_slice(&[7]);
+        let key = |method: &str| MethodKey {
+            target,
+            method: method.to_string(),
+        };
+        let wrapped = MethodConfig {
+            forward_caller: Some(true),
+            ..MethodConfig::cycles(1)
+        };
+        set_config(key("a"), wrapped);
+        schedule_config(
+            key("b"),
+            PendingConfig {
+                config: Some(MethodConfig::cycles(1)),
+                effective_at: u64::MAX,
+            },
+        );
+        set_config(
+            MethodKey {
+                target: Principal::anonymous(),
+                method: "a".to_string(),
+            },
+            MethodConfig::cycles(1),
+        );
+        let mut settings = envelope_settings(&target, &ConfigKey::Method(key("c")));
+        settings.sort_unstable();
+        assert_eq!(settings, vec![false, true]);
+        assert_eq!(
+            envelope_settings(&target, &ConfigKey::Method(key("b"))),
+            vec![true]
+        );
+    }
+
+    #[test]
+    fn scheduled_rule_change_applies_when_due() {
+        let rule = |method_prefix: &str| PricingRule {
+            target: Principal::anonymous(),
+            method_prefix: method_prefix.to_string(),
+        };
+        set_rule(rule(""), MethodConfig::cycles(1));
+        schedule_rule(
+            rule("get"),
+            PendingConfig {
+                config: Some(MethodConfig::cycles(2)),
+                effective_at: 100,
+            },
+        );
+        assert_eq!(rule_price(&rule("get")), Some(MethodConfig::cycles(1)));
+        apply_due_configs(100);
+        assert_eq!(rule_price(&rule("get")), Some(MethodConfig::cycles(2)));
+        assert_eq!(rule_price(&rule("get_x")), Some(MethodConfig::cycles(2)));
+        assert_eq!(
+            price_without_rule(&rule("get")),
+            Some(MethodConfig::cycles(1))
+        );
+        assert_eq!(price_without_rule(&rule("")), None);
+        assert!(list_pending_rules().is_empty());
+    }
+}
@@ -0,0 +1,302 @@
+//! Where the bridge keeps its configuration and bookkeeping.
+//!
+//! [`crate::api::call::Bridge`] reads prices and records the effects of calls
+//! through a [`ConfigStore`]. The standalone wrapper canister uses
+//! `StateStore`, which keeps everything in its stable memory. A canister that
+//! embeds the bridge may supply its own store instead; only pricing and
+//! request tracking must be implemented, and every other feature is off until
+//! its methods are.
+
+use candid::Principal;
+use ic_papi_api::cycles::cycles_ledger_canister_id;
+use ic_papi_api::PaymentType;
+
+use crate::domain::errors::BridgeError;
+use crate::domain::types::{
+    BridgeReply, CacheKey, FeeDenom, MethodConfig, MethodKey, RequestKey, TokenCyclesRate,
+    CYCLES_LEDGER_FEE,
+};
+#[cfg(feature = "canister")]
+use crate::state;
+
+/// The configuration and bookkeeping the bridge needs.
+pub trait ConfigStore {
+    /// The price of a `(target, method)` pair, if it may be called.
+    fn resolve_config(&self, key: &MethodKey) -> Option<MethodConfig>;
+
+    /// Start tracking a call made with a request id. Returns the outcome to
+    /// replay if the caller already made the call, and `None` if it should go
+    /// ahead. A retry must have the original call's
+    /// [`BridgeCallArgs::fingerprint`](crate::domain::types::BridgeCallArgs::fingerprint).
+    ///
+    /// # Errors
+    /// If the call may not go ahead.
+    fn begin_request(
+        &self,
+        key: RequestKey,
+        target: Principal,
+        method: &str,
+        fingerprint: [u8; 32],
+        now: u64,
+    ) -> Result<Option<Result<BridgeReply, BridgeError>>, BridgeError>;
+
+    /// Record the outcome of a call started with [`ConfigStore::begin_request`].
+    fn complete_request(&self, key: &RequestKey, outcome: Result<BridgeReply, BridgeError>);
+
+    /// Forget a call started with [`ConfigStore::begin_request`] that was not
+    /// charged.
+    fn abandon_request(&self, key: &RequestKey);
+
+    /// Apply the price changes that are due at `now`.
+    fn apply_due_configs(&self, _now: u64) {}
+
+    /// Fails if the method, or its whole target, is paused.
+    ///
+    /// # Errors
+    /// If calls to the method are paused.
+    fn check_not_paused(&self, _key: &MethodKey) -> Result<(), BridgeError> {
+        Ok(())
+    }
+
+    /// Fails if the target's circuit breaker is open at `now`.
+    ///
+    /// # Errors
+    /// If the breaker is open.
+    fn check_circuit(&self, _target: Principal, _now: u64) -> Result<(), BridgeError> {
+        Ok(())
+    }
+
+    /// Record that a forwarded call to `target` succeeded.
+    fn record_success(&self, _target: &Principal) {}
+
+    /// Record that a forwarded call to `target` failed at `now`, through the
+    /// target's own fault.
+    fn record_failure(&self, _target: Principal, _now: u64) {}
+
+    /// Whether the module hash of `target` is pinned.
+    fn is_module_pinned(&self, _target: &Principal) -> bool {
+        false
+    }
+
+    /// Fails if `target` is known to run other code than its pin.
+    ///
+    /// # Errors
+    /// If a change of module has been recorded.
+    fn check_module_pin(&self, _target: Principal) -> Result<(), BridgeError> {
+        Ok(())
+    }
+
+    /// Whether `target` was recently found to run its pinned module, so that
+    /// it need not be looked up again at `now`.
+    fn module_recently_verified(&self, _target: &Principal, _now: u64) -> bool {
+        false
+    }
+
+    /// Compare the module `observed` on `target` with its pin.
+    ///
+    /// # Errors
+    /// If the target runs other code, now or when last checked.
+    fn record_module_hash(
+        &self,
+        _target: Principal,
+        _observed: Option<Vec<u8>>,
+        _now: u64,
+    ) -> Result<(), BridgeError> {
+        Ok(())
+    }
+
+    /// Look up an unexpired cached reply.
+    fn get_cached_reply(&self, _key: &CacheKey, _now: u64) -> Option<Vec<u8>> {
+        None
+    }
+
+    /// Cache a reply until `expires_at`.
+    fn put_cached_reply(&self, _key: CacheKey, _reply: Vec<u8>, _now: u64, _expires_at: u64) {}
+
+    /// The token-to-cycles rate of `ledger`, needed to forward cycles on calls
+    /// paid in its tokens.
+    fn get_rate(&self, _ledger: &Principal) -> Option<TokenCyclesRate> {
+        None
+    }
+
+    /// The cycles available to fund calls paid in tokens.
+    fn reserve_balance(&self) -> u128 {
+        0
+    }
+
+    /// Take `amount` cycles out of the reserve.
+    ///
+    /// # Errors
+    /// Returns the available balance if the reserve cannot cover `amount`.
+    fn take_from_reserve(&self, _amount: u128) -> Result<(), u128> {
+        Err(self.reserve_balance())
+    }
+
+    /// Put back cycles taken with [`ConfigStore::take_from_reserve`].
+    fn return_to_reserve(&self, _amount: u128) {}
+
+    /// The fee `ledger` charges for a transfer, if it is known.
+    fn get_ledger_fee(&self, ledger: &Principal) -> Option<u128> {
+        (*ledger == cycles_ledger_canister_id()).then_some(CYCLES_LEDGER_FEE)
+    }
+
+    /// Credit the owner of `target`, if it has one, with the `revenue` of a call.
+    fn credit_owner(&self, _target: &Principal, _denom: FeeDenom, _revenue: u128) {}
+
+    /// The payment type `caller` uses when it does not name one, recording
+    /// that it was used at `now`.
+    fn use_payment_preference(&self, _caller: &Principal, _now: u64) -> Option<PaymentType> {
+        None
+    }
+}
+
+/// The store of the standalone wrapper canister, backed by [`crate::state`].
+#[cfg(feature = "canister")]
+pub struct StateStore;
+
+#[cfg(feature = "canister")]
+impl ConfigStore for StateStore {
+    fn resolve_config(&self, key: &MethodKey) -> Option<MethodConfig> {
+        state::resolve_config(key)
+    }
+
+    fn begin_request(
+        &self,
+        key: RequestKey,
+        target: Principal,
+        method: &str,
+        fingerprint: [u8; 32],
+        now: u64,
+    ) -> Result<Option<Result<BridgeReply, BridgeError>>, BridgeError> {
+        state::begin_request(key, target, method, fingerprint, now)
+    }
+
+    fn complete_request(&self, key: &RequestKey, outcome: Result<BridgeReply, BridgeError>) {
+        state::complete_request(key, outcome);
+    }
+
+    fn abandon_request(&self, key: &RequestKey) {
+        state::abandon_request(key);
+    }
+
+    fn apply_due_configs(&self, now: u64) {
+        state::apply_due_configs(now);
+    }
+
+    fn check_not_paused(&self, key: &MethodKey) -> Result<(), BridgeError> {
+        state::check_not_paused(key)
+    }
+
+    fn check_circuit(&self, target: Principal, now: u64) -> Result<(), BridgeError> {
+        state::check_circuit(target, now)
+    }
+
+    fn record_success(&self, target: &Principal) {
+        state::record_success(target);
+    }
+
+    fn record_failure(&self, target: Principal, now: u64) {
+        state::record_failure(target, now);
+    }
+
+    fn is_module_pinned(&self, target: &Principal) -> bool {
+        state::get_module_pin(target).is_some()
+    }
+
+    fn check_module_pin(&self, target: Principal) -> Result<(), BridgeError> {
+        state::check_module_pin(target)
+    }
+
+    fn module_recently_verified(&self, target: &Principal, now: u64) -> bool {
+        state::module_recently_verified(target, now)
+    }
+
+    fn record_module_hash(
+        &self,
+        target: Principal,
+        observed: Option<Vec<u8>>,
+        now: u64,
+    ) -> Result<(), BridgeError> {
+        state::record_module_hash(target, observed, now)
+    }
+
+    fn get_cached_reply(&self, key: &CacheKey, now: u64) -> Option<Vec<u8>> {
+        state::get_cached_reply(key, now)
+    }
+
+    fn put_cached_reply(&self, key: CacheKey, reply: Vec<u8>, now: u64, expires_at: u64) {
+        state::put_cached_reply(key, reply, now, expires_at);
+    }
+
+    fn get_rate(&self, ledger: &Principal) -> Option<TokenCyclesRate> {
+        state::get_rate(ledger)
+    }
+
+    fn reserve_balance(&self) -> u128 {
+        state::get_reserve().balance
+    }
+
+    fn take_from_reserve(&self, amount: u128) -> Result<(), u128> {
+        state::take_from_reserve(amount)
+    }
+
+    fn return_to_reserve(&self, amount: u128) {
+        state::return_to_reserve(amount);
+    }
+
+    fn get_ledger_fee(&self, ledger: &Principal) -> Option<u128> {
+        state::get_ledger_fee(ledger)
+    }
+
+    fn credit_owner(&self, target: &Principal, denom: FeeDenom, revenue: u128) {
+        let _ = state::credit_owner(target, denom, revenue);
+    }
+
+    fn use_payment_preference(&self, caller: &Principal, now: u64) -> Option<PaymentType> {
+        state::use_payment_preference(caller, now)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A store that only prices calls and tracks no requests.
+    struct PricesOnly;
+
+    impl ConfigStore for PricesOnly {
+        fn resolve_config(&self, _key: &MethodKey) -> Option<MethodConfig> {
+            None
+        }
+
+        fn begin_request(
+            &self,
+            _key: RequestKey,
+            _target: Principal,
+            _method: &str,
+            _fingerprint: [u8; 32],
+            _now: u64,
+        ) -> Result<Option<Result<BridgeReply, BridgeError>>, BridgeError> {
+            Ok(None)
+        }
+
+        fn complete_request(&self, _key: &RequestKey, _outcome: Result<BridgeReply, BridgeError>) {}
+
+        fn abandon_request(&self, _key: &RequestKey) {}
+    }
+
+    #[test]
+    fn optional_features_are_off_by_default() {
+        let store = PricesOnly;
+        let target = Principal::anonymous();
+        assert_eq!(store.take_from_reserve(1), Err(0));
+        assert_eq!(store.get_rate(&target), None);
+        assert!(!store.is_module_pinned(&target));
+        assert_eq!(store.use_payment_preference(&target, 0), None);
+        assert_eq!(
+            store.get_ledger_fee(&cycles_ledger_canister_id()),
+            Some(CYCLES_LEDGER_FEE)
+        );
+        assert_eq!(store.get_ledger_fee(&target), None);
+    }
+}
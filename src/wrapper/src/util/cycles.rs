@@ -1,21 +1,61 @@
 use candid::Principal;
-use ic_cdk::call::Call;
-use ic_cdk::call::Response;
+use ic_cdk::api::msg_cycles_refunded;
+use ic_cdk::call::{Call, CallErrorExt, CallFailed, Response};
+
+use crate::domain::errors::{RejectCode, TargetReject};
+use crate::domain::types::CallWait;
 
 /// Forwards a raw candid call to the target canister, optionally attaching cycles.
 ///
-/// # Errors
-/// Returns a string with reject code/message if the target rejects.
+/// Returns the result of the call and the attached cycles that the target did
+/// not accept. The result is an error with the reject code and message if the
+/// target rejects, and says whether the target may nonetheless have executed
+/// the call. A bounded-wait call that times out is rejected with
+/// [`RejectCode::SysUnknown`].
 pub async fn forward_raw(
     target: Principal,
     method: &str,
     args: Vec<u8>,
     cycles: u128,
-) -> Result<Vec<u8>, String> {
-    Call::bounded_wait(target, method)
-        .with_cycles(cycles)
-        .with_raw_args(&args)
-        .await
-        .map(Response::into_bytes)
-        .map_err(|e| e.to_string())
+    wait: CallWait,
+) -> (Result<Vec<u8>, TargetReject>, u128) {
+    let call = match wait {
+        CallWait::Bounded { timeout_seconds } => {
+            let call = Call::bounded_wait(target, method);
+            match timeout_seconds {
+                Some(timeout) => call.change_timeout(timeout),
+                None => call,
+            }
+        }
+        CallWait::Unbounded => Call::unbounded_wait(target, method),
+    };
+    let result = call.with_cycles(cycles).with_raw_args(&args).await;
+    // Cycles are only refunded with a reply or reject. A call that was never
+    // made kept all its cycles, and `msg_cycles_refunded` would still report
+    // the refund of whatever call was answered before.
+    let cycles_unused = match &result {
+        Ok(_) | Err(CallFailed::CallRejected(_)) => msg_cycles_refunded(),
+        Err(_) => cycles,
+    };
+    (
+        result
+            .map(Response::into_bytes)
+            .map_err(|e| target_reject(&e)),
+        cycles_unused,
+    )
+}
+
+fn target_reject(e: &CallFailed) -> TargetReject {
+    let (reject_code, message) = match e {
+        CallFailed::CallRejected(rejected) => (
+            Some(RejectCode::from(rejected.raw_reject_code())),
+            rejected.reject_message().to_string(),
+        ),
+        _ => (None, e.to_string()),
+    };
+    TargetReject {
+        reject_code,
+        message,
+        may_have_executed: !e.is_clean_reject(),
+    }
 }
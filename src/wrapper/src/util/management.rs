@@ -0,0 +1,20 @@
+use candid::Principal;
+use ic_cdk::call::Call;
+use ic_management_canister_types::{CanisterInfoArgs, CanisterInfoResult};
+
+/// Asks the management canister for the controllers and module hash of `canister_id`.
+///
+/// # Errors
+/// Returns a description of the failure if the management canister cannot be
+/// reached or rejects the request, e.g. because the canister does not exist.
+pub async fn canister_info(canister_id: Principal) -> Result<CanisterInfoResult, String> {
+    Call::bounded_wait(Principal::management_canister(), "canister_info")
+        .with_arg(CanisterInfoArgs {
+            canister_id,
+            num_requested_changes: None,
+        })
+        .await
+        .map_err(|err| format!("Failed to look up canister {canister_id}: {err}"))?
+        .candid::<CanisterInfoResult>()
+        .map_err(|err| format!("Unexpected canister_info response for {canister_id}: {err}"))
+}
@@ -1 +1,4 @@
 pub mod cycles;
+pub mod management;
+#[cfg(feature = "canister")]
+pub mod storable;
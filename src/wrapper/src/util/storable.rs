@@ -0,0 +1,213 @@
+//! Encoding of the wrapper's state in stable structures.
+//!
+//! Every key and value is stored as a one-byte schema version followed by its
+//! Candid encoding. When a stored value type changes in a way Candid cannot
+//! absorb (anything beyond adding optional fields), bump its
+//! [`Versioned::VERSION`] and override [`Versioned::decode`] to convert values
+//! written with older versions. Values are converted as they are read, so an
+//! upgrade never has to rewrite the whole state.
+//!
+//! Keys are compared by their encoding, so that looking one up does not decode
+//! the keys it passes. The encoding of a key type must therefore never change;
+//! to change it, move the entries to a map in a new memory.
+
+use candid::{decode_one, encode_one, CandidType, Principal};
+use ic_papi_api::PaymentType;
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::Storable;
+use serde::de::DeserializeOwned;
+use std::any::type_name;
+use std::borrow::Cow;
+use std::cmp::Ordering;
+use std::collections::BTreeSet;
+use std::marker::PhantomData;
+
+use crate::domain::types::{
+    ConfigChange, FeeDenom, MethodConfig, MethodKey, ModulePin, PendingConfig, PricingRule, Role,
+    StoredRequest, TokenCyclesRate,
+};
+
+/// A type kept in stable memory, with the version of its stored schema.
+pub trait Versioned: CandidType + DeserializeOwned {
+    /// The schema version that values are written with.
+    const VERSION: u8 = 1;
+
+    /// Decode a value written with schema `version`.
+    ///
+    /// # Panics
+    /// If `version` is unknown or the value does not decode. Reading state
+    /// that cannot be understood must stop the call rather than lose data.
+    fn decode(version: u8, bytes: &[u8]) -> Self {
+        assert!(
+            version == Self::VERSION,
+            "Unknown schema version {version} of {}",
+            type_name::<Self>()
+        );
+        decode_one(bytes).unwrap_or_else(|err| {
+            panic!(
+                "Failed to decode stored {} (schema version {version}): {err}",
+                type_name::<Self>()
+            )
+        })
+    }
+}
+
+fn encode<T: Versioned>(value: &T) -> Vec<u8> {
+    let mut bytes = vec![T::VERSION];
+    bytes.extend(
+        encode_one(value).unwrap_or_else(|err| {
+            panic!("Failed to encode {} for storage: {err}", type_name::<T>())
+        }),
+    );
+    bytes
+}
+
+fn decode<T: Versioned>(bytes: &[u8]) -> T {
+    let (version, bytes) = bytes
+        .split_first()
+        .unwrap_or_else(|| panic!("Stored {} is empty", type_name::<T>()));
+    T::decode(*version, bytes)
+}
+
+/// A value of a stable structure.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Stored<T>(pub T);
+
+impl<T: Versioned> Storable for Stored<T> {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(encode(&self.0))
+    }
+
+    fn into_bytes(self) -> Vec<u8> {
+        encode(&self.0)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Self(decode(&bytes))
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+/// A key of a stable structure, held and ordered in its encoded form.
+#[derive(Debug)]
+pub struct Key<T> {
+    bytes: Vec<u8>,
+    key: PhantomData<T>,
+}
+
+impl<T: Versioned> Key<T> {
+    #[must_use]
+    pub fn new(key: &T) -> Self {
+        Self::from_vec(encode(key))
+    }
+
+    /// The key that `bytes`, as returned by [`Key::as_bytes`], encode.
+    #[must_use]
+    pub fn from_vec(bytes: Vec<u8>) -> Self {
+        Self {
+            bytes,
+            key: PhantomData,
+        }
+    }
+
+    /// Decode the key.
+    #[must_use]
+    pub fn get(&self) -> T {
+        decode(&self.bytes)
+    }
+
+    #[must_use]
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+}
+
+impl<T> Clone for Key<T> {
+    fn clone(&self) -> Self {
+        Self {
+            bytes: self.bytes.clone(),
+            key: PhantomData,
+        }
+    }
+}
+
+impl<T> PartialEq for Key<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.bytes == other.bytes
+    }
+}
+
+impl<T> Eq for Key<T> {}
+
+impl<T> PartialOrd for Key<T> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<T> Ord for Key<T> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.bytes.cmp(&other.bytes)
+    }
+}
+
+impl<T> Storable for Key<T> {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Borrowed(&self.bytes)
+    }
+
+    fn into_bytes(self) -> Vec<u8> {
+        self.bytes
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Self {
+            bytes: bytes.into_owned(),
+            key: PhantomData,
+        }
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Versioned for Principal {}
+impl Versioned for u64 {}
+impl Versioned for u128 {}
+impl Versioned for MethodKey {}
+impl Versioned for MethodConfig {}
+impl Versioned for PendingConfig {}
+impl Versioned for PricingRule {}
+impl Versioned for ConfigChange {}
+impl Versioned for BTreeSet<Role> {}
+impl Versioned for TokenCyclesRate {}
+impl Versioned for (Principal, FeeDenom) {}
+impl Versioned for (Principal, String) {}
+impl Versioned for StoredRequest {}
+impl Versioned for ModulePin {}
+impl Versioned for PaymentType {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn values_round_trip_with_their_version() {
+        let key = MethodKey {
+            target: Principal::anonymous(),
+            method: "m".to_string(),
+        };
+        let bytes = Stored(key.clone()).into_bytes();
+        assert_eq!(bytes[0], MethodKey::VERSION);
+        assert_eq!(Stored::<MethodKey>::from_bytes(Cow::Owned(bytes)).0, key);
+        assert_eq!(Key::new(&key).get(), key);
+    }
+
+    #[test]
+    #[should_panic(expected = "Unknown schema version")]
+    fn unknown_version_is_refused() {
+        let mut bytes = Stored(1_u128).into_bytes();
+        bytes[0] = u128::VERSION + 1;
+        let _ = Stored::<u128>::from_bytes(Cow::Owned(bytes));
+    }
+}
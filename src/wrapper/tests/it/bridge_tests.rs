@@ -1,8 +1,15 @@
-use crate::util::pic_canister::PicCanisterTrait;
+use crate::util::pic_canister::{PicCanister, PicCanisterTrait};
 use crate::util::test_environment::TestSetup;
-use candid::{decode_one, encode_args, Principal};
+use candid::{decode_one, encode_args, CandidType, Principal};
 use ic_papi_api::PaymentType;
-use ic_papi_wrapper::domain::types::{Call0Args, FeeDenom, FeeSpec, MethodConfig, MethodKey};
+use ic_papi_guard::guards::any::VendorPaymentConfig;
+use ic_papi_wrapper::domain::errors::BridgeError;
+use ic_papi_wrapper::domain::types::{
+    BatchCall, BridgeReply, Call0Args, CallBatchArgs, FeeSpec, MethodConfig, MethodKey,
+    MethodPrices, Role,
+};
+use pocket_ic::common::rest::BlobCompression;
+use serde_bytes::ByteBuf;
 
 #[test]
 fn bridge_call_fails_if_target_is_self() {
@@ -11,32 +18,66 @@ fn bridge_call_fails_if_target_is_self() {
         target: setup.wrapper.canister_id(),
         method: "any".to_string(),
         payment: Some(PaymentType::AttachedCycles),
+        forward_cycles: None,
+        request_id: None,
+        max_fee: None,
     };
 
-    let result: Result<Result<Vec<u8>, String>, String> =
+    let result: Result<Result<BridgeReply, BridgeError>, String> =
         setup.wrapper.update(setup.user, "call0", args);
     let inner_result = result.expect("Failed to reach canister");
     let err = inner_result.expect_err("Should have returned an error");
-    assert!(err.contains("Self-calls are not allowed through the bridge."));
+    assert_eq!(err, BridgeError::SelfCall);
 }
 
 #[test]
 fn bridge_call_fails_if_target_is_management_canister() {
-    // The management canister is a forbidden target: proxying to it would run
-    // with the bridge's own principal as caller. This is rejected before any
-    // pricing lookup, so no configuration is needed for the test.
+    // Lifecycle and settings methods of the management canister are forbidden:
+    // proxying to them would run with the bridge's own principal as caller.
+    // This is rejected before any pricing lookup, so no configuration is
+    // needed for the test.
     let setup = TestSetup::default();
     let args = Call0Args {
         target: Principal::management_canister(),
         method: "update_settings".to_string(),
         payment: Some(PaymentType::AttachedCycles),
+        forward_cycles: None,
+        request_id: None,
+        max_fee: None,
     };
 
-    let result: Result<Result<Vec<u8>, String>, String> =
+    let result: Result<Result<BridgeReply, BridgeError>, String> =
         setup.wrapper.update(setup.user, "call0", args);
     let inner_result = result.expect("Failed to reach canister");
     let err = inner_result.expect_err("Should have returned an error");
-    assert!(err.contains("the management canister may not be reached through the bridge."));
+    assert!(
+        matches!(err, BridgeError::ForbiddenTarget(_)),
+        "unexpected error: {err}"
+    );
+}
+
+#[test]
+fn bridge_call_lets_priceable_management_method_through() {
+    // `raw_rand` is on the management allowlist, so it passes the target check
+    // and fails only because the operator has not priced it.
+    let setup = TestSetup::default();
+    let args = Call0Args {
+        target: Principal::management_canister(),
+        method: "raw_rand".to_string(),
+        payment: Some(PaymentType::AttachedCycles),
+        forward_cycles: None,
+        request_id: None,
+        max_fee: None,
+    };
+
+    let result: Result<Result<BridgeReply, BridgeError>, String> =
+        setup.wrapper.update(setup.user, "call0", args);
+    let inner_result = result.expect("Failed to reach canister");
+    let err = inner_result.expect_err("Should have failed: method not configured");
+    assert!(
+        matches!(err, BridgeError::MethodNotConfigured { .. }),
+        "unexpected error: {err}"
+    );
 }
 
 #[test]
@@ -49,14 +90,17 @@ fn bridge_call_fails_if_method_not_configured() {
         target: setup.target.canister_id(),
         method: "unconfigured_method".to_string(),
         payment: Some(PaymentType::AttachedCycles),
+        forward_cycles: None,
+        request_id: None,
+        max_fee: None,
     };
 
-    let result: Result<Result<Vec<u8>, String>, String> =
+    let result: Result<Result<BridgeReply, BridgeError>, String> =
         setup.wrapper.update(setup.user, "call0", args);
     let inner_result = result.expect("Failed to reach canister");
     let err = inner_result.expect_err("Should have failed: method not configured");
     assert!(
-        err.contains("No price is configured"),
+        matches!(err, BridgeError::MethodNotConfigured { .. }),
         "unexpected error: {err}"
     );
 }
@@ -70,14 +114,7 @@ fn set_method_config_requires_controller() {
         target: setup.target.canister_id(),
         method: "any".to_string(),
     };
-    let config = MethodConfig {
-        fee: FeeSpec {
-            amount: 0,
-            denom: FeeDenom::Cycles,
-        },
-        supported: vec![],
-        forward_cycles: None,
-    };
+    let config = MethodConfig::cycles(0);
 
     let bytes = setup
         .pic
@@ -92,3 +129,175 @@ fn set_method_config_requires_controller() {
     let err = res.expect_err("A non-controller must not be able to set config");
     assert!(err.contains("controller"), "unexpected error: {err}");
 }
+
+#[test]
+fn call_batch_fails_before_charging_if_any_call_is_not_configured() {
+    // Every call in a batch is priced before the single payment is taken, so a
+    // batch containing an unpriced method is rejected as a whole.
+    let setup = TestSetup::default();
+    let args = CallBatchArgs {
+        calls: vec![BatchCall {
+            target: setup.target.canister_id(),
+            method: "unconfigured_method".to_string(),
+            args_blob: ByteBuf::from(candid::encode_one(()).unwrap()),
+            forward_cycles: None,
+            max_fee: None,
+        }],
+        payment: Some(PaymentType::AttachedCycles),
+        parallel: None,
+    };
+
+    let result: Result<Result<Vec<Result<BridgeReply, BridgeError>>, BridgeError>, String> =
+        setup.wrapper.update(setup.user, "call_batch", args);
+    let err = result
+        .expect("Failed to reach canister")
+        .expect_err("Should have failed: method not configured");
+    assert!(
+        matches!(err, BridgeError::MethodNotConfigured { .. }),
+        "unexpected error: {err}"
+    );
+}
+
+#[test]
+fn call_batch_rejects_an_empty_batch() {
+    let setup = TestSetup::default();
+    let args = CallBatchArgs {
+        calls: vec![],
+        payment: None,
+        parallel: None,
+    };
+
+    let result: Result<Result<Vec<Result<BridgeReply, BridgeError>>, BridgeError>, String> =
+        setup.wrapper.update(setup.user, "call_batch", args);
+    let err = result
+        .expect("Failed to reach canister")
+        .expect_err("An empty batch must be rejected");
+    assert_eq!(err, BridgeError::EmptyBatch);
+}
+
+#[test]
+fn grant_role_requires_admin() {
+    // Only controllers and admins may hand out roles, or anyone could make
+    // themselves a pricing manager.
+    let setup = TestSetup::default();
+    let bytes = setup
+        .pic
+        .update_call(
+            setup.wrapper.canister_id(),
+            setup.user, // neither a controller nor an admin
+            "grant_role",
+            encode_args((setup.user, Role::PricingManager)).unwrap(),
+        )
+        .expect("Failed to reach canister");
+    let res: Result<bool, String> = decode_one(&bytes).unwrap();
+    let err = res.expect_err("A non-admin must not be able to grant roles");
+    assert!(err.contains("Admin"), "unexpected error: {err}");
+}
+
+#[test]
+fn configuration_survives_an_upgrade() {
+    // State lives in stable structures, so an upgrade neither saves nor
+    // restores it.
+    let setup = TestSetup::default();
+    let controller = Principal::anonymous();
+    let key = MethodKey {
+        target: setup.target.canister_id(),
+        method: "any".to_string(),
+    };
+    let config = MethodConfig::cycles(1_000);
+    let bytes = setup
+        .pic
+        .update_call(
+            setup.wrapper.canister_id(),
+            controller,
+            "set_method_config",
+            encode_args((key.clone(), config.clone())).unwrap(),
+        )
+        .expect("Failed to reach canister");
+    let res: Result<(), String> = decode_one(&bytes).unwrap();
+    res.expect("A controller may set a price");
+
+    let wasm = std::fs::read(PicCanister::cargo_wasm_path("ic_papi_wrapper")).unwrap();
+    setup
+        .pic
+        .upgrade_canister(
+            setup.wrapper.canister_id(),
+            wasm,
+            encode_args(()).unwrap(),
+            Some(controller),
+        )
+        .expect("Failed to upgrade the wrapper");
+
+    let prices: MethodPrices = setup
+        .wrapper
+        .query(setup.user, "get_method_config", key)
+        .expect("Failed to reach canister");
+    assert_eq!(prices.current, Some(config));
+}
+
+#[test]
+fn legacy_state_is_migrated_on_upgrade() {
+    /// A price as the first release stored it, before any optional behaviour
+    /// was added.
+    #[derive(CandidType)]
+    struct FirstReleaseConfig {
+        fee: FeeSpec,
+        supported: Vec<VendorPaymentConfig>,
+        forward_cycles: Option<u128>,
+    }
+    let setup = TestSetup::default();
+    let controller = Principal::anonymous();
+    let key = MethodKey {
+        target: setup.target.canister_id(),
+        method: "any".to_string(),
+    };
+    let config = MethodConfig {
+        forward_cycles: Some(500),
+        ..MethodConfig::cycles(1_000)
+    };
+    // The first release serialized its price registry into stable memory in
+    // `pre_upgrade`.
+    let legacy = (vec![(
+        key.clone(),
+        FirstReleaseConfig {
+            fee: config.fee.clone(),
+            supported: vec![],
+            forward_cycles: config.forward_cycles,
+        },
+    )],);
+    setup.pic.set_stable_memory(
+        setup.wrapper.canister_id(),
+        encode_args(legacy).unwrap(),
+        BlobCompression::NoCompression,
+    );
+
+    let wasm = std::fs::read(PicCanister::cargo_wasm_path("ic_papi_wrapper")).unwrap();
+    setup
+        .pic
+        .upgrade_canister(
+            setup.wrapper.canister_id(),
+            wasm,
+            encode_args(()).unwrap(),
+            Some(controller),
+        )
+        .expect("Failed to migrate the legacy state");
+
+    let prices: MethodPrices = setup
+        .wrapper
+        .query(setup.user, "get_method_config", key)
+        .expect("Failed to reach canister");
+    assert_eq!(prices.current, Some(config));
+
+    // Everything the first release did not keep starts from its defaults.
+    let bytes = setup
+        .pic
+        .query_call(
+            setup.wrapper.canister_id(),
+            controller,
+            "get_platform_fee",
+            encode_args(()).unwrap(),
+        )
+        .expect("Failed to reach canister");
+    let platform_fee: u32 = decode_one(&bytes).unwrap();
+    assert_eq!(platform_fee, 500);
+}